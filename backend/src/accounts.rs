@@ -0,0 +1,228 @@
+use std::{fmt, net::IpAddr, sync::Arc, time::Instant};
+
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use once_cell::sync::Lazy;
+use rand::{rngs::OsRng, RngCore};
+use rocket::{http::Status, tokio::{sync::Semaphore, task}};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::config::ChatConfig;
+use crate::limits::IpLimiter;
+use crate::moderation;
+use crate::storage::{self, Account, AccountStore, StorageError};
+use crate::usernames::{self, UsernameError};
+
+const MIN_PASSWORD_LEN: usize = 8;
+/// Hashing is slow on purpose, so very long passwords are refused.
+const MAX_PASSWORD_LEN: usize = 256;
+const TOKEN_BYTES: usize = 32;
+/// Passwords hashed at once; each hash takes about 19 MiB.
+const MAX_CONCURRENT_HASHES: usize = 4;
+
+/// Verified against when the username is unknown, so that logins take
+/// as long whether or not the account exists.
+static DUMMY_HASH: Lazy<String> = Lazy::new(|| {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(b"not the password of anyone", &salt)
+        .map(|hash| hash.to_string())
+        .unwrap_or_default()
+});
+
+
+#[derive(Debug)]
+pub enum AccountError {
+    InvalidUsername(UsernameError),
+    WeakPassword,
+    InvalidCredentials,
+    RateLimited,
+    Internal(String),
+}
+
+impl AccountError {
+    pub fn status(&self) -> Status {
+        match self {
+            AccountError::InvalidUsername(UsernameError::Taken) => Status::Conflict,
+            AccountError::InvalidUsername(_) | AccountError::WeakPassword => Status::BadRequest,
+            AccountError::InvalidCredentials => Status::Unauthorized,
+            AccountError::RateLimited => Status::TooManyRequests,
+            AccountError::Internal(_) => Status::InternalServerError,
+        }
+    }
+}
+
+impl fmt::Display for AccountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccountError::InvalidUsername(err) => write!(f, "{}", err),
+            AccountError::WeakPassword => write!(
+                f, "password must be {} to {} characters long", MIN_PASSWORD_LEN, MAX_PASSWORD_LEN,
+            ),
+            AccountError::InvalidCredentials => write!(f, "wrong username or password"),
+            AccountError::RateLimited => write!(f, "too many attempts, try again later"),
+            AccountError::Internal(_) => write!(f, "internal error"),
+        }
+    }
+}
+
+impl From<StorageError> for AccountError {
+    fn from(err: StorageError) -> AccountError {
+        AccountError::Internal(err.to_string())
+    }
+}
+
+/// A login session. The token is only known to the client.
+#[derive(Serialize)]
+pub struct Session {
+    pub token: String,
+    pub username: String,
+    pub expires_at: NaiveDateTime,
+}
+
+/// Sessions are stored under this hash, so a leaked database does not
+/// leak usable tokens.
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+fn new_token() -> String {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Registration and password login. Hashing and store calls run on the
+/// blocking pool to keep them off the async workers, a few passwords are
+/// hashed at a time, and every address may only ask for so many.
+pub struct Accounts {
+    store: Arc<dyn AccountStore>,
+    session_lifetime: TimeDelta,
+    limiter: IpLimiter,
+    hashing: Semaphore,
+    config: Arc<ChatConfig>,
+}
+
+impl Accounts {
+    pub fn new(store: Arc<dyn AccountStore>, config: Arc<ChatConfig>) -> Accounts {
+        let session_lifetime = TimeDelta::try_seconds(config.session_lifetime as i64)
+            .unwrap_or_else(TimeDelta::max_value);
+        Accounts {
+            store,
+            session_lifetime,
+            limiter: IpLimiter::new(config.account_limit),
+            hashing: Semaphore::new(MAX_CONCURRENT_HASHES),
+            config,
+        }
+    }
+
+    fn check_limit(&self, ip: Option<IpAddr>) -> Result<(), AccountError> {
+        if self.limiter.try_take(ip, Instant::now()) {
+            Ok(())
+        } else {
+            Err(AccountError::RateLimited)
+        }
+    }
+
+    /// Runs password hashing on the blocking pool once a hashing slot is free.
+    async fn hash<T, F>(&self, hash: F) -> Result<T, AccountError>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T, argon2::password_hash::Error> + Send + 'static,
+    {
+        let _permit = self.hashing.acquire().await
+            .map_err(|err| AccountError::Internal(err.to_string()))?;
+        task::spawn_blocking(hash)
+            .await
+            .map_err(|err| AccountError::Internal(err.to_string()))?
+            .map_err(|err| AccountError::Internal(err.to_string()))
+    }
+
+    /// Registers an account for anyone who asks. Names that look like those
+    /// of moderators and admins are refused, as they would come with the
+    /// role; their accounts are created with `add_account`.
+    pub async fn register(&self, ip: Option<IpAddr>, username: String, password: String) -> Result<(), AccountError> {
+        self.check_limit(ip)?;
+        let username = usernames::normalize(&username).map_err(AccountError::InvalidUsername)?;
+        if moderation::is_staff_name(&self.config, &username) {
+            return Err(AccountError::InvalidUsername(UsernameError::Reserved));
+        }
+        self.add_account(username, password).await
+    }
+
+    /// Creates an account under any valid name, staff names included.
+    /// Only for the operator, see `main`.
+    pub async fn add_account(&self, username: String, password: String) -> Result<(), AccountError> {
+        let username = usernames::normalize(&username).map_err(AccountError::InvalidUsername)?;
+        let password_len = password.chars().count();
+        if !(MIN_PASSWORD_LEN..=MAX_PASSWORD_LEN).contains(&password_len) {
+            return Err(AccountError::WeakPassword);
+        }
+
+        let password_hash = self.hash(move || {
+            let salt = SaltString::generate(&mut OsRng);
+            Argon2::default()
+                .hash_password(password.as_bytes(), &salt)
+                .map(|hash| hash.to_string())
+        }).await?;
+
+        let account = Account {
+            skeleton: usernames::skeleton_key(&username),
+            username,
+            password_hash,
+            created_at: Utc::now().naive_utc(),
+        };
+        let store = self.store.clone();
+        let username = account.username.clone();
+        if !storage::run_blocking(move || store.create_account(&account)).await? {
+            return Err(AccountError::InvalidUsername(UsernameError::Taken));
+        }
+        log::info!("Registered account {}", username);
+        Ok(())
+    }
+
+    /// Logs in with a password. Unknown usernames are checked against a
+    /// dummy hash, so they fail no faster than wrong passwords.
+    pub async fn login(&self, ip: Option<IpAddr>, username: String, password: String) -> Result<Session, AccountError> {
+        self.check_limit(ip)?;
+        if password.chars().count() > MAX_PASSWORD_LEN {
+            return Err(AccountError::InvalidCredentials);
+        }
+        let username = usernames::normalize(&username).map_err(|_| AccountError::InvalidCredentials)?;
+        let store = self.store.clone();
+        let account = storage::run_blocking(move || store.account(&username)).await?;
+
+        let password_hash = account.as_ref().map(|account| account.password_hash.clone());
+        let verified = self.hash(move || {
+            // The dummy hash is made on first use, so on this thread too.
+            PasswordHash::new(password_hash.as_deref().unwrap_or(&DUMMY_HASH))
+                .map(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+        }).await?;
+        let account = match account {
+            Some(account) if verified => account,
+            _ => return Err(AccountError::InvalidCredentials),
+        };
+
+        let token = new_token();
+        let expires_at = Utc::now().naive_utc()
+            .checked_add_signed(self.session_lifetime)
+            .unwrap_or(NaiveDateTime::MAX);
+        let (store, token_hash, username) = (self.store.clone(), hash_token(&token), account.username.clone());
+        storage::run_blocking(move || store.create_session(&token_hash, &username, expires_at)).await?;
+        Ok(Session {
+            token,
+            username: account.username,
+            expires_at,
+        })
+    }
+
+    pub async fn logout(&self, token: &str) -> Result<(), AccountError> {
+        let (store, token_hash) = (self.store.clone(), hash_token(token));
+        storage::run_blocking(move || store.delete_session(&token_hash)).await?;
+        Ok(())
+    }
+}
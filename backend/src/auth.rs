@@ -0,0 +1,149 @@
+use std::{fmt, sync::Arc};
+
+use rocket::{
+    http::Status,
+    request::{FromRequest, Outcome, Request},
+};
+
+use crate::config::{AuthKind, ChatConfig};
+use crate::storage::AccountStore;
+
+mod jwt;
+mod sessions;
+mod tokens;
+
+pub use jwt::JwtAuthenticator;
+pub use sessions::SessionAuthenticator;
+pub use tokens::TokenFileAuthenticator;
+
+
+/// Who is behind a connection, as proven by its token.
+#[derive(Clone, Debug)]
+pub struct Identity {
+    pub username: String,
+}
+
+#[derive(Debug)]
+pub struct AuthError(pub String);
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "auth error: {}", self.0)
+    }
+}
+
+/// Checks the bearer tokens clients connect with.
+#[rocket::async_trait]
+pub trait Authenticator: Send + Sync {
+    /// Returns the identity the token was issued to.
+    async fn authenticate(&self, token: &str) -> Result<Identity, AuthError>;
+}
+
+/// Authenticators of the server, tried in order. Account sessions are
+/// only accepted in the modes that use accounts, so nobody can register a
+/// name that belongs to a token or JWT identity. Unless a token is
+/// required, connections without one are anonymous, which is only fine
+/// on a trusted network.
+pub struct Auth {
+    authenticators: Vec<Arc<dyn Authenticator>>,
+    required: bool,
+}
+
+pub fn from_config(config: &ChatConfig, accounts: Arc<dyn AccountStore>) -> Auth {
+    let mut authenticators: Vec<Arc<dyn Authenticator>> = Vec::new();
+    match config.auth {
+        AuthKind::None => {
+            log::warn!("Authentication is optional, anyone can connect");
+            authenticators.push(Arc::new(SessionAuthenticator::new(accounts)));
+        },
+        AuthKind::Accounts => {
+            log::info!("Using account sessions");
+            authenticators.push(Arc::new(SessionAuthenticator::new(accounts)));
+        },
+        AuthKind::Tokens => {
+            log::info!("Using tokens from {}", config.auth_tokens_path);
+            let authenticator = TokenFileAuthenticator::open(&config.auth_tokens_path)
+                .expect("Cannot read auth tokens");
+            authenticators.push(Arc::new(authenticator));
+        },
+        AuthKind::Jwt => {
+            log::info!("Using jwt authentication");
+            let authenticator = JwtAuthenticator::new(&config.jwt_secret)
+                .expect("Cannot set up jwt authentication");
+            authenticators.push(Arc::new(authenticator));
+        },
+    }
+    Auth {
+        authenticators,
+        required: config.auth != AuthKind::None,
+    }
+}
+
+impl Auth {
+    async fn authenticate(&self, token: &str) -> Result<Identity, AuthError> {
+        let mut last_err = AuthError("no authenticator".to_string());
+        for authenticator in self.authenticators.iter() {
+            match authenticator.authenticate(token).await {
+                Ok(identity) => return Ok(identity),
+                Err(err) => last_err = err,
+            }
+        }
+        Err(last_err)
+    }
+}
+
+/// Token of a request, from the `Authorization: Bearer` header or, for
+/// browsers that cannot set headers on websockets, the `token` query parameter.
+fn bearer_token<'r>(req: &'r Request<'_>) -> Option<&'r str> {
+    if let Some(header) = req.headers().get_one("Authorization") {
+        return header.strip_prefix("Bearer ").map(str::trim);
+    }
+    req.query_value::<&str>("token").and_then(Result::ok)
+}
+
+/// Bearer token of a request, whether or not it is valid.
+pub struct BearerToken<'r>(pub &'r str);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for BearerToken<'r> {
+    type Error = AuthError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match bearer_token(req) {
+            Some(token) => Outcome::Success(BearerToken(token)),
+            None => Outcome::Error((Status::Unauthorized, AuthError("token is missing".to_string()))),
+        }
+    }
+}
+
+/// Request guard of websocket upgrades and history requests. Rejects the
+/// request with 401 if it carries an invalid token, or none while
+/// authentication is required.
+pub struct Authenticated(pub Option<Identity>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Authenticated {
+    type Error = AuthError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let auth = match req.rocket().state::<Auth>() {
+            Some(auth) => auth,
+            None => return Outcome::Error((Status::InternalServerError, AuthError("auth is not set up".to_string()))),
+        };
+        let token = match bearer_token(req) {
+            Some(token) => token,
+            None if auth.required => {
+                log::warn!("Rejecting connection without a token");
+                return Outcome::Error((Status::Unauthorized, AuthError("token is missing".to_string())));
+            },
+            None => return Outcome::Success(Authenticated(None)),
+        };
+        match auth.authenticate(token).await {
+            Ok(identity) => Outcome::Success(Authenticated(Some(identity))),
+            Err(err) => {
+                log::warn!("Rejecting connection: {}", err);
+                Outcome::Error((Status::Unauthorized, err))
+            }
+        }
+    }
+}
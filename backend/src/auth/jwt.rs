@@ -0,0 +1,112 @@
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+
+use crate::usernames;
+
+use super::{AuthError, Authenticator, Identity};
+
+
+#[derive(Deserialize)]
+struct Claims {
+    /// Username the token was issued to.
+    sub: String,
+}
+
+/// Accepts JWTs signed with a shared HMAC key. Tokens must carry a valid
+/// username in `sub` and an `exp` that has not passed yet.
+pub struct JwtAuthenticator {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+impl JwtAuthenticator {
+    pub fn new(secret: &str) -> Result<JwtAuthenticator, AuthError> {
+        if secret.is_empty() {
+            return Err(AuthError("jwt_secret is not set".to_string()));
+        }
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.algorithms = vec![Algorithm::HS256, Algorithm::HS384, Algorithm::HS512];
+        validation.set_required_spec_claims(&["exp", "sub"]);
+        Ok(JwtAuthenticator {
+            key: DecodingKey::from_secret(secret.as_bytes()),
+            validation,
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl Authenticator for JwtAuthenticator {
+    async fn authenticate(&self, token: &str) -> Result<Identity, AuthError> {
+        let data = decode::<Claims>(token, &self.key, &self.validation)
+            .map_err(|err| AuthError(format!("invalid jwt: {}", err)))?;
+        let username = usernames::normalize(&data.claims.sub)
+            .map_err(|err| AuthError(format!("jwt subject is invalid: {}", err)))?;
+        Ok(Identity { username })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde::Serialize;
+
+    use super::*;
+
+    const SECRET: &str = "test secret";
+    /// `{"alg":"none","typ":"JWT"}`, base64url encoded.
+    const NONE_HEADER: &str = "eyJhbGciOiJub25lIiwidHlwIjoiSldUIn0";
+
+    #[derive(Serialize)]
+    struct TestClaims<'a> {
+        sub: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        exp: Option<i64>,
+    }
+
+    fn token(algorithm: Algorithm, secret: &str, sub: &str, exp: Option<i64>) -> String {
+        encode(&Header::new(algorithm), &TestClaims { sub, exp }, &EncodingKey::from_secret(secret.as_bytes())).unwrap()
+    }
+
+    fn in_an_hour() -> Option<i64> {
+        Some(Utc::now().timestamp() + 3600)
+    }
+
+    #[rocket::async_test]
+    async fn accepts_hmac_tokens_with_a_valid_subject() {
+        let auth = JwtAuthenticator::new(SECRET).unwrap();
+        for algorithm in [Algorithm::HS256, Algorithm::HS384, Algorithm::HS512] {
+            let identity = auth.authenticate(&token(algorithm, SECRET, " Cafe\u{301}", in_an_hour())).await.unwrap();
+            assert_eq!(identity.username, "Caf\u{e9}");
+        }
+    }
+
+    #[rocket::async_test]
+    async fn rejects_expired_and_unsigned_tokens() {
+        let auth = JwtAuthenticator::new(SECRET).unwrap();
+        let expired = Some(Utc::now().timestamp() - 3600);
+        assert!(auth.authenticate(&token(Algorithm::HS256, SECRET, "alice", expired)).await.is_err());
+        assert!(auth.authenticate(&token(Algorithm::HS256, SECRET, "alice", None)).await.is_err());
+        assert!(auth.authenticate(&token(Algorithm::HS256, "other secret", "alice", in_an_hour())).await.is_err());
+
+        let signed = token(Algorithm::HS256, SECRET, "alice", in_an_hour());
+        let (_, rest) = signed.split_once('.').unwrap();
+        let (claims, _) = rest.split_once('.').unwrap();
+        let unsigned = format!("{}.{}.", NONE_HEADER, claims);
+        assert!(auth.authenticate(&unsigned).await.is_err());
+    }
+
+    #[rocket::async_test]
+    async fn rejects_invalid_subjects() {
+        let auth = JwtAuthenticator::new(SECRET).unwrap();
+        for sub in ["", "system", "Adm1n", "b\u{43e}b", "bob\u{202e}"] {
+            let result = auth.authenticate(&token(Algorithm::HS256, SECRET, sub, in_an_hour())).await;
+            assert!(result.is_err(), "{:?}", sub);
+        }
+    }
+
+    #[test]
+    fn needs_a_secret() {
+        assert!(JwtAuthenticator::new("").is_err());
+    }
+}
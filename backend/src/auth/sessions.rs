@@ -0,0 +1,33 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+
+use crate::accounts::hash_token;
+use crate::storage::{self, AccountStore};
+
+use super::{AuthError, Authenticator, Identity};
+
+
+/// Accepts session tokens issued by `/accounts/login`.
+pub struct SessionAuthenticator {
+    store: Arc<dyn AccountStore>,
+}
+
+impl SessionAuthenticator {
+    pub fn new(store: Arc<dyn AccountStore>) -> SessionAuthenticator {
+        SessionAuthenticator { store }
+    }
+}
+
+#[rocket::async_trait]
+impl Authenticator for SessionAuthenticator {
+    async fn authenticate(&self, token: &str) -> Result<Identity, AuthError> {
+        let now = Utc::now().naive_utc();
+        let (store, token_hash) = (self.store.clone(), hash_token(token));
+        match storage::run_blocking(move || store.session(&token_hash, now)).await {
+            Ok(Some(username)) => Ok(Identity { username }),
+            Ok(None) => Err(AuthError("unknown or expired session".to_string())),
+            Err(err) => Err(AuthError(err.to_string())),
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use std::{collections::HashMap, fs};
+
+use crate::usernames;
+
+use super::{AuthError, Authenticator, Identity};
+
+
+/// Static tokens read from a file at startup. Every line holds a token and
+/// the username it belongs to, separated by whitespace. Empty lines and
+/// lines starting with `#` are skipped. Usernames have to follow the same
+/// rules as those users pick.
+pub struct TokenFileAuthenticator {
+    users: HashMap<String, String>,
+}
+
+impl TokenFileAuthenticator {
+    pub fn open(path: &str) -> Result<TokenFileAuthenticator, AuthError> {
+        let content = fs::read_to_string(path)
+            .map_err(|err| AuthError(format!("cannot read {}: {}", path, err)))?;
+        TokenFileAuthenticator::parse(&content)
+    }
+
+    fn parse(content: &str) -> Result<TokenFileAuthenticator, AuthError> {
+        let mut users = HashMap::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once(char::is_whitespace) {
+                Some((token, username)) if !username.trim().is_empty() => {
+                    let username = usernames::normalize(username)
+                        .map_err(|err| AuthError(format!("line {}: {}", number + 1, err)))?;
+                    users.insert(token.to_string(), username);
+                },
+                _ => return Err(AuthError(format!("line {} has no username", number + 1))),
+            }
+        }
+        Ok(TokenFileAuthenticator { users })
+    }
+}
+
+#[rocket::async_trait]
+impl Authenticator for TokenFileAuthenticator {
+    async fn authenticate(&self, token: &str) -> Result<Identity, AuthError> {
+        match self.users.get(token) {
+            Some(username) => Ok(Identity { username: username.clone() }),
+            None => Err(AuthError("unknown token".to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_tokens_and_skips_comments() {
+        let tokens = TokenFileAuthenticator::parse("# staff\n\nsecret1 alice\n  secret2\tCafe\u{301} au lait  \n").unwrap();
+        assert_eq!(tokens.users.len(), 2);
+        assert_eq!(tokens.users["secret1"], "alice");
+        assert_eq!(tokens.users["secret2"], "Caf\u{e9} au lait");
+    }
+
+    #[test]
+    fn rejects_lines_without_a_valid_username() {
+        for (content, line) in [
+            ("secret1 alice\nsecret2\n", "line 2"),
+            ("secret1 admin\n", "line 1"),
+            ("secret1 b\u{43e}b\n", "line 1"),
+            ("# tokens\nsecret1 bob\u{7}\n", "line 2"),
+        ] {
+            let err = TokenFileAuthenticator::parse(content).err().expect(content);
+            assert!(err.0.starts_with(line), "{}: {}", content, err);
+        }
+    }
+
+    #[rocket::async_test]
+    async fn authenticates_known_tokens() {
+        let tokens = TokenFileAuthenticator::parse("secret1 alice").unwrap();
+        assert_eq!(tokens.authenticate("secret1").await.unwrap().username, "alice");
+        assert!(tokens.authenticate("secret2").await.is_err());
+        assert!(tokens.authenticate("alice").await.is_err());
+    }
+}
@@ -0,0 +1,140 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    net::IpAddr,
+    sync::Arc,
+};
+
+use rocket::futures::stream::BoxStream;
+use serde::{Deserialize, Serialize};
+
+use common::{Presence, ServerMessage};
+
+use crate::config::{BusKind, ChatConfig};
+use crate::moderation::Sanction;
+
+mod memory;
+mod redis;
+
+pub use memory::MemoryBus;
+pub use self::redis::RedisBus;
+
+
+/// Who is connected to one instance, and the rooms they joined.
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
+pub struct Members {
+    /// Every connected username, also those in no room.
+    pub users: Vec<String>,
+    pub rooms: BTreeMap<String, Vec<String>>,
+    /// Presence of the users that are not simply online.
+    #[serde(default)]
+    pub presence: BTreeMap<String, Presence>,
+}
+
+impl Members {
+    /// Rooms whose users or their presence differ between the two
+    /// snapshots.
+    pub fn changed_rooms(&self, other: &Members) -> BTreeSet<String> {
+        self.rooms.keys()
+            .chain(other.rooms.keys())
+            .filter(|room| {
+                let users = self.rooms.get(*room);
+                users != other.rooms.get(*room)
+                    || users.into_iter().flatten().any(|user| self.presence.get(user) != other.presence.get(user))
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Presence of a user of this instance.
+    pub fn presence_of(&self, username: &str) -> Presence {
+        self.presence.get(username).cloned().unwrap_or_default()
+    }
+}
+
+/// What instances tell each other.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "kind")]
+pub enum Event {
+    /// A message for every user of a room.
+    Room { room: String, message: ServerMessage },
+    /// A direct message for every connection of a user.
+    Direct { username: String, message: ServerMessage },
+    /// Everyone connected to the sending instance. Sent when it changes
+    /// and every `bus_heartbeat` seconds.
+    Members { members: Members },
+    /// Asks every instance to send its `Members`, e.g. after a restart.
+    Sync,
+    /// An authenticated user connected; anonymous users with a name that
+    /// looks like theirs are closed.
+    Claim { username: String },
+    /// A moderator kicked, banned or muted a user.
+    Moderate {
+        username: String,
+        sanction: Sanction,
+        by: String,
+        reason: Option<String>,
+    },
+    /// A moderator banned an address.
+    BanAddress {
+        ip: IpAddr,
+        by: String,
+        reason: Option<String>,
+    },
+    /// The sending instance is shutting down.
+    Gone,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Envelope {
+    /// Instance that published the event.
+    pub origin: String,
+    pub event: Event,
+}
+
+#[derive(Debug)]
+pub struct BusError(pub String);
+
+impl fmt::Display for BusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "bus error: {}", self.0)
+    }
+}
+
+impl From<::redis::RedisError> for BusError {
+    fn from(err: ::redis::RedisError) -> BusError {
+        BusError(err.to_string())
+    }
+}
+
+impl From<serde_json::Error> for BusError {
+    fn from(err: serde_json::Error) -> BusError {
+        BusError(err.to_string())
+    }
+}
+
+/// Channel between all backend instances. Every instance receives what
+/// any of them publishes, its own envelopes included.
+#[rocket::async_trait]
+pub trait Bus: Send + Sync {
+    async fn publish(&self, envelope: &Envelope) -> Result<(), BusError>;
+
+    /// Envelopes published from now on. The stream ends if the bus
+    /// connection is lost.
+    async fn subscribe(&self) -> Result<BoxStream<'static, Envelope>, BusError>;
+}
+
+pub fn from_config(config: &ChatConfig) -> Arc<dyn Bus> {
+    match config.bus {
+        BusKind::Memory => {
+            log::info!("Using in-process bus, running a single instance");
+            Arc::new(MemoryBus::new())
+        },
+        BusKind::Redis => {
+            log::info!("Using redis bus at {}, channel {}", config.redis_url, config.redis_channel);
+            let bus = RedisBus::open(&config.redis_url, &config.redis_channel)
+                .expect("Cannot open redis bus");
+            Arc::new(bus)
+        },
+    }
+}
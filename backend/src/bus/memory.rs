@@ -0,0 +1,47 @@
+use rocket::{
+    futures::stream::{self, BoxStream, StreamExt},
+    tokio::sync::broadcast::{self, error::RecvError},
+};
+
+use super::{Bus, BusError, Envelope};
+
+const CAPACITY: usize = 1024;
+
+
+/// Bus of a single instance, delivering envelopes within the process.
+pub struct MemoryBus {
+    sender: broadcast::Sender<Envelope>,
+}
+
+impl MemoryBus {
+    pub fn new() -> MemoryBus {
+        MemoryBus {
+            sender: broadcast::channel(CAPACITY).0,
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Bus for MemoryBus {
+    async fn publish(&self, envelope: &Envelope) -> Result<(), BusError> {
+        // No subscriber is not an error, there is just nobody to tell.
+        let _ = self.sender.send(envelope.clone());
+        Ok(())
+    }
+
+    async fn subscribe(&self) -> Result<BoxStream<'static, Envelope>, BusError> {
+        let receiver = self.sender.subscribe();
+        let envelopes = stream::unfold(receiver, |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(envelope) => return Some((envelope, receiver)),
+                    Err(RecvError::Lagged(skipped)) => {
+                        log::warn!("Bus subscriber lagged behind, skipped {} envelopes", skipped);
+                    },
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        });
+        Ok(envelopes.boxed())
+    }
+}
@@ -0,0 +1,68 @@
+use redis::{aio::MultiplexedConnection, AsyncCommands, Client};
+use rocket::{
+    futures::stream::{BoxStream, StreamExt},
+    tokio::sync::Mutex,
+};
+
+use super::{Bus, BusError, Envelope};
+
+
+/// Bus over a redis pub/sub channel, shared by every instance that uses
+/// the same server and channel.
+pub struct RedisBus {
+    client: Client,
+    channel: String,
+    /// Connection used for publishing, opened on first use and again
+    /// after it failed.
+    connection: Mutex<Option<MultiplexedConnection>>,
+}
+
+impl RedisBus {
+    pub fn open(url: &str, channel: &str) -> Result<RedisBus, BusError> {
+        Ok(RedisBus {
+            client: Client::open(url)?,
+            channel: channel.to_string(),
+            connection: Mutex::default(),
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl Bus for RedisBus {
+    async fn publish(&self, envelope: &Envelope) -> Result<(), BusError> {
+        let payload = serde_json::to_string(envelope)?;
+        let mut connection = self.connection.lock().await;
+        let conn = match connection.as_mut() {
+            Some(conn) => conn,
+            None => connection.insert(self.client.get_multiplexed_async_connection().await?),
+        };
+        let result: Result<i64, _> = conn.publish(&self.channel, payload).await;
+        if result.is_err() {
+            *connection = None;
+        }
+        result?;
+        Ok(())
+    }
+
+    async fn subscribe(&self) -> Result<BoxStream<'static, Envelope>, BusError> {
+        let mut pubsub = self.client.get_async_pubsub().await?;
+        pubsub.subscribe(&self.channel).await?;
+        let envelopes = pubsub.into_on_message().filter_map(|msg| async move {
+            let payload: String = match msg.get_payload() {
+                Ok(payload) => payload,
+                Err(err) => {
+                    log::warn!("Cannot read bus message: {}", err);
+                    return None;
+                }
+            };
+            match serde_json::from_str(&payload) {
+                Ok(envelope) => Some(envelope),
+                Err(err) => {
+                    log::warn!("Cannot parse bus message: {}", err);
+                    None
+                }
+            }
+        });
+        Ok(envelopes.boxed())
+    }
+}
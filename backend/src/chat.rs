@@ -1,15 +1,27 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use std::collections::HashMap;
+use rocket::tokio::{self, sync::Mutex, time::Instant};
 
-use rocket::{
-    futures::{stream::SplitSink, SinkExt}, 
-    tokio::sync::Mutex,
-};
-use rocket_ws::{Message, stream::DuplexStream};
-use log;
+use common::{ChatError, ChatMessage, ErrorCode, Presence, Reaction, ServerMessage};
+
+use crate::bus::Event;
+use crate::config::ChatConfig;
+use crate::fanout::FanOut;
+use crate::outbox::Outbox;
+use crate::protocol::ClientProtocol;
+use crate::storage::{self, EventKind, MessageReactions, MessageStore, StoredEvent};
 
-use common::{WebSocketMessage, WebSocketMessageType};
 
+const MAX_EMOJI_LEN: usize = 16;
+/// Start of the owner key of authenticated connections.
+pub const USER_OWNER_PREFIX: &str = "user:";
 
 pub enum UserStatus {
     Join,
@@ -18,164 +30,552 @@ pub enum UserStatus {
 
 pub struct ChatRoomConnection {
     pub username: String,
-    pub sink: SplitSink<DuplexStream, Message>,
+    /// See `ChatSession::owner`.
+    pub owner: String,
+    pub presence: Presence,
+    pub outbox: Outbox,
+    pub protocol: Arc<ClientProtocol>,
 }
 
 impl ChatRoomConnection {
-    fn new(username: String, sink: SplitSink<DuplexStream, Message>) -> ChatRoomConnection {
+    pub fn new(username: String, owner: String, presence: Presence, outbox: Outbox, protocol: Arc<ClientProtocol>) -> ChatRoomConnection {
         ChatRoomConnection {
             username,
-            sink,
+            owner,
+            presence,
+            outbox,
+            protocol,
         }
     }
+
+    /// Whether the connection was authenticated, so its name is its own.
+    pub fn is_authenticated(&self) -> bool {
+        self.owner.starts_with(USER_OWNER_PREFIX)
+    }
+
+    /// Queues the message for the socket in the client's protocol, without
+    /// waiting for it to be written. Returns whether it was queued.
+    pub fn send(&self, msg: &ServerMessage) -> bool {
+        self.outbox.send(&self.protocol, msg)
+    }
 }
 
-#[derive(Default)]
 pub struct ChatRoom {
+    pub name: String,
     pub connections: Mutex<HashMap<usize, ChatRoomConnection>>,
+    /// Held while an event is stored and sent, and while a user joins, so
+    /// users get events in the order of their ids and none twice. Store
+    /// calls happen under it, never under the connections lock.
+    sequence: Mutex<()>,
+    /// Users on their way in, see `reserve`.
+    joining: AtomicUsize,
+    /// Users currently typing and when they last said so.
+    typing: Mutex<HashMap<usize, Instant>>,
+    store: Arc<dyn MessageStore>,
+    fanout: Arc<FanOut>,
+    config: Arc<ChatConfig>,
 }
 
 impl ChatRoom {
-    pub async fn add(&self, user_id: usize, ws_sink: SplitSink<DuplexStream, Message>) {
-        let username = {
-            let mut conns = self.connections.lock().await;
-            let username = format!("user #{}", user_id);
-            let connection = ChatRoomConnection::new(username.clone(), ws_sink);
-            conns.insert(user_id, connection);
-            username
-        };
+    pub fn new(name: String, store: Arc<dyn MessageStore>, fanout: Arc<FanOut>, config: Arc<ChatConfig>) -> ChatRoom {
+        ChatRoom {
+            name,
+            connections: Mutex::default(),
+            sequence: Mutex::default(),
+            joining: AtomicUsize::new(0),
+            typing: Mutex::default(),
+            store,
+            fanout,
+            config,
+        }
+    }
+
+    /// Keeps the room from being dropped as empty until the user it is
+    /// reserved for is inserted. Taken under the registry lock, so the
+    /// slow part of joining can happen without it.
+    pub fn reserve(&self) {
+        self.joining.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Adds the connection a `reserve` was taken for and sends it the
+    /// recent history and read markers. Both happen under the sequence
+    /// lock, so no message is missed or repeated.
+    pub async fn insert(&self, user_id: usize, connection: ChatRoomConnection) {
+        {
+            let _sequence = self.sequence.lock().await;
+            let history = self.history().await;
+            connection.send(&ServerMessage::History { room: self.name.clone(), messages: history });
+            for (username, message_id) in self.read_markers().await {
+                connection.send(&ServerMessage::Read { room: self.name.clone(), username, message_id });
+            }
+            self.connections.lock().await.insert(user_id, connection);
+        }
+        self.joining.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    async fn history(&self) -> Vec<ChatMessage> {
+        let (store, room, limit) = (self.store.clone(), self.name.clone(), self.config.history_size);
+        match storage::run_blocking(move || store.recent(&room, limit)).await {
+            Ok(events) => self.with_reactions(events).await,
+            Err(err) => {
+                log::warn!("Cannot load history of room {}: {}", self.name, err);
+                Vec::new()
+            }
+        }
+    }
+
+    async fn read_markers(&self) -> BTreeMap<String, u64> {
+        let (store, room) = (self.store.clone(), self.name.clone());
+        storage::run_blocking(move || store.read_markers(&room)).await.unwrap_or_else(|err| {
+            log::warn!("Cannot load read markers of room {}: {}", self.name, err);
+            BTreeMap::new()
+        })
+    }
+
+    /// Messages of the events with their stored reactions. They go without
+    /// if those cannot be loaded.
+    async fn with_reactions(&self, events: Vec<StoredEvent>) -> Vec<ChatMessage> {
+        let message_ids: Vec<u64> = events.iter().filter_map(|event| event.message.id).collect();
+        let store = self.store.clone();
+        let reactions = storage::run_blocking(move || store.reactions(&message_ids)).await.unwrap_or_else(|err| {
+            log::warn!("Cannot load reactions in room {}: {}", self.name, err);
+            HashMap::new()
+        });
+        events.into_iter()
+            .map(|event| {
+                let mut msg = event.message;
+                if let Some(msg_reactions) = msg.id.and_then(|id| reactions.get(&id)) {
+                    msg.reactions = to_reactions(msg_reactions);
+                }
+                msg
+            })
+            .collect()
+    }
+
+    /// Loads a stored message, making sure it belongs to this room.
+    async fn load_message(&self, message_id: u64) -> Result<StoredEvent, ChatError> {
+        let store = self.store.clone();
+        match storage::run_blocking(move || store.get(message_id)).await {
+            Ok(Some(event)) if event.room == self.name => Ok(event),
+            Ok(_) => Err(ChatError::new(
+                ErrorCode::NotFound,
+                format!("Message {} is not in room {}", message_id, self.name),
+            )),
+            Err(err) => {
+                log::warn!("Cannot load message {}: {}", message_id, err);
+                Err(internal_error())
+            }
+        }
+    }
+
+    /// Loads a message of this room that can be replied or reacted to.
+    async fn load_live_message(&self, message_id: u64) -> Result<StoredEvent, ChatError> {
+        let event = self.load_message(message_id).await?;
+        if event.kind != EventKind::Message || event.message.deleted {
+            return Err(ChatError::new(ErrorCode::NotFound, format!("Message {} was deleted", message_id)));
+        }
+        Ok(event)
+    }
+
+    pub async fn announce_join(&self, user_id: usize, username: String) {
         self.send_username(user_id).await;
         self.broadcast_users_list().await;
         self.update_status(username, UserStatus::Join).await;
     }
 
-    pub fn parse_message(&self, msg: String) -> Option<WebSocketMessage> {
-        let new_msg: WebSocketMessage = match serde_json::from_str(msg.as_str()) {
-            Ok(new_msg) => new_msg,
-            Err(_) => {
-                log::warn!("Cannot deserialize json message");
-                return None;
-            }
+    /// Whether the room has no users and none on their way in.
+    pub async fn is_empty(&self) -> bool {
+        self.joining.load(Ordering::SeqCst) == 0 && self.connections.lock().await.is_empty()
+    }
+
+    /// Stores the message and assigns it the id given by the store. Call
+    /// it under the sequence lock.
+    async fn persist(&self, kind: EventKind, message: &mut ChatMessage, owner: Option<String>) {
+        let event = StoredEvent {
+            room: self.name.clone(),
+            kind,
+            message: message.clone(),
+            owner,
         };
-        Some(new_msg)
+        let store = self.store.clone();
+        match storage::run_blocking(move || store.append(&event)).await {
+            Ok(id) => message.id = Some(id),
+            Err(err) => {
+                log::warn!("Cannot persist {} event in room {}: {}", kind.as_str(), self.name, err);
+            }
+        }
     }
 
-    pub async fn update_status(&self, username: String, status: UserStatus) {
-        let mut conns = self.connections.lock().await;
+    async fn system_msg(&self, text: String) -> ServerMessage {
+        let mut message = ChatMessage::new(text, "system".to_string());
+        self.persist(EventKind::System, &mut message, None).await;
+        ServerMessage::System {
+            room: Some(self.name.clone()),
+            message,
+        }
+    }
+
+    /// Users of this room on every instance. Each instance sends its own
+    /// users the list, so it is never published.
+    fn users_list_msg(&self, conns: &HashMap<usize, ChatRoomConnection>) -> ServerMessage {
+        let mut users: Vec<(String, Presence)> = conns.values()
+            .map(|conn| (conn.username.clone(), conn.presence.clone()))
+            .collect();
+        users.extend(self.fanout.remote_users(&self.name));
+        // Users connected more than once, here or elsewhere, are listed once.
+        let usernames: BTreeSet<String> = users.iter().map(|(username, _)| username.clone()).collect();
+        ServerMessage::UserList {
+            room: self.name.clone(),
+            users: usernames.into_iter().collect(),
+            presence: merge_presence(users),
+        }
+    }
 
+    /// Users of this room connected to this instance.
+    pub async fn usernames(&self) -> Vec<String> {
+        self.connections.lock().await.values().map(|conn| conn.username.clone()).collect()
+    }
+
+    /// Sends the message to the users of this room on other instances.
+    fn publish(&self, msg: &ServerMessage) {
+        self.fanout.publish(Event::Room {
+            room: self.name.clone(),
+            message: msg.clone(),
+        });
+    }
+
+    /// Delivers a message another instance published to this room. What
+    /// it changed is in the shared store already.
+    pub async fn deliver(&self, msg: ServerMessage) {
+        self.broadcast(&msg).await;
+    }
+
+    pub async fn update_status(&self, username: String, status: UserStatus) {
         let msg = match status {
             UserStatus::Join => format!("{} join the chat", username.clone()),
             UserStatus::Left => format!("{} left the chat", username),
         };
-        let msg_out = WebSocketMessage::from_system_msg(msg).to_string();
-        for (_, conn) in conns.iter_mut() {
-            let _ = conn.sink.send(Message::Text(msg_out.clone())).await;
-        }
+        self.announce(msg).await;
+    }
+
+    /// Stores a system event and sends it to the users of this room on
+    /// every instance.
+    pub async fn announce(&self, text: String) {
+        let _sequence = self.sequence.lock().await;
+        let msg_out = self.system_msg(text).await;
+        self.publish(&msg_out);
+        self.broadcast(&msg_out).await;
     }
 
     pub async fn change_username(&self, user_id: usize, new_username: String) {
-        let mut conns = self.connections.lock().await;
-        let user_conn = match conns.get_mut(&user_id) {
-            Some(conn) => conn,
+        let old_username = match self.connections.lock().await.get_mut(&user_id) {
+            Some(user_conn) => std::mem::replace(&mut user_conn.username, new_username.clone()),
             _ => {
                 log::warn!("Cannot find a user");
                 return;
             }
         };
-        let old_username = user_conn.username.clone();
-        user_conn.username = new_username.clone();
-        
-        let update_msg = WebSocketMessage::from_username(new_username.clone()).to_string();
-        let _ = user_conn.sink.send(Message::Text(update_msg)).await;
 
-        let users: Vec<String> = conns.iter().map(|(_, conn)| conn.username.clone()).collect();
-        let users_list_msg = WebSocketMessage::from_users_list(users).to_string();
-        let system_msg = format!("{} changed username to {}", old_username, new_username);
-        let system_msg = WebSocketMessage::from_system_msg(system_msg).to_string();
+        let (store, room, old, new) = (self.store.clone(), self.name.clone(), old_username.clone(), new_username.clone());
+        if let Err(err) = storage::run_blocking(move || store.rename_user(&room, &old, &new)).await {
+            log::warn!("Cannot move reactions and read marker of {} in room {}: {}", old_username, self.name, err);
+        }
 
-        for (_, conn) in conns.iter_mut() {
-            let _ = conn.sink.send(Message::Text(system_msg.clone())).await;
-            let _ = conn.sink.send(Message::Text(users_list_msg.clone())).await;
+        self.announce(format!("{} changed username to {}", old_username, new_username)).await;
+        self.broadcast_users_list().await;
+    }
+
+    /// Sets the presence of the user and sends the room a new user list.
+    pub async fn set_presence(&self, user_id: usize, presence: Presence) {
+        let mut conns = self.connections.lock().await;
+        match conns.get_mut(&user_id) {
+            Some(conn) => conn.presence = presence,
+            None => {
+                log::warn!("Cannot find a user {}", user_id);
+                return;
+            }
+        }
+        let msg_out = self.users_list_msg(&conns);
+        for conn in conns.values() {
+            conn.send(&msg_out);
         }
     }
 
     pub async fn send_username(&self, user_id: usize) {
-        let mut conns = self.connections.lock().await;
-        if let Some(user_conn) = conns.get_mut(&user_id) {
-            let msg = WebSocketMessage::from_username(user_conn.username.clone()).to_string();
-            let _ = user_conn.sink.send(Message::Text(msg)).await;
+        let conns = self.connections.lock().await;
+        if let Some(user_conn) = conns.get(&user_id) {
+            let msg = ServerMessage::UsernameChange {
+                room: Some(self.name.clone()),
+                username: user_conn.username.clone(),
+            };
+            user_conn.send(&msg);
         } else {
             log::warn!("Cannot find a user {}", user_id);
         }
     }
 
-    pub async fn broadcast(&self, msg: Message) {
-        let mut conns = self.connections.lock().await;
-        for (_, conn) in conns.iter_mut() {
-            let _ = conn.sink.send(msg.clone()).await;
-        } 
+    /// Sends the message to the users of this room on this instance only.
+    pub async fn broadcast(&self, msg: &ServerMessage) {
+        let conns = self.connections.lock().await;
+        for conn in conns.values() {
+            conn.send(msg);
+        }
     }
 
-    pub async fn broadcast_message(&self, msg: WebSocketMessage, user_id: usize) {
-        let mut chat_msg = match msg.message {
-            Some(msg) => msg,
-            _ => {
-                log::warn!("Message to broadcast from user {} is empty", user_id);
-                return;
+    pub async fn broadcast_message(&self, text: String, reply_to: Option<u64>, user_id: usize) -> Result<(), ChatError> {
+        let (username, owner) = {
+            let conns = self.connections.lock().await;
+            let user_conn = connection_of(&conns, user_id)?;
+            (user_conn.username.clone(), user_conn.owner.clone())
+        };
+        let mut chat_msg = ChatMessage {
+            reply_to,
+            ..ChatMessage::new(text, username)
+        };
+
+        if let Some(parent_id) = reply_to {
+            self.load_live_message(parent_id).await?;
+        }
+
+        // Sending a message ends typing; clients clear the indicator themselves.
+        self.typing.lock().await.remove(&user_id);
+
+        let _sequence = self.sequence.lock().await;
+        self.persist(EventKind::Message, &mut chat_msg, Some(owner)).await;
+        let message_id = chat_msg.id;
+        let msg_out = ServerMessage::NewMessage { room: self.name.clone(), message: chat_msg };
+        self.publish(&msg_out);
+        let conns = self.connections.lock().await;
+        // Connections on other instances get it over the bus and are not counted.
+        let mut delivered = 0;
+        for (id, conn) in conns.iter() {
+            if conn.send(&msg_out) && *id != user_id {
+                delivered += 1;
             }
+        }
+
+        // Only stored messages are acked, the id is what clients track.
+        if let (Some(message_id), Some(user_conn)) = (message_id, conns.get(&user_id)) {
+            user_conn.send(&ServerMessage::Ack { room: self.name.clone(), message_id, delivered });
+        }
+        Ok(())
+    }
+
+    /// Moves the user's read marker forward to `message_id` and tells the
+    /// authors of the messages it passed, on any instance. Everyone else
+    /// gets the markers when they join.
+    pub async fn mark_read(&self, user_id: usize, message_id: u64) -> Result<(), ChatError> {
+        let username = username_of(&*self.connections.lock().await, user_id)?;
+        self.load_message(message_id).await?;
+
+        let _sequence = self.sequence.lock().await;
+        let (store, room, reader) = (self.store.clone(), self.name.clone(), username.clone());
+        let authors = storage::run_blocking(move || match store.mark_read(&room, &reader, message_id)? {
+            Some(previous) => store.authors(&room, previous, message_id),
+            None => Ok(BTreeSet::new()),
+        }).await;
+        let mut authors = authors.map_err(|err| {
+            log::warn!("Cannot store read marker of {} in room {}: {}", username, self.name, err);
+            internal_error()
+        })?;
+        authors.remove(&username);
+        if authors.is_empty() {
+            return Ok(());
+        }
+
+        let msg_out = ServerMessage::Read { room: self.name.clone(), username, message_id };
+        for conn in self.connections.lock().await.values().filter(|conn| authors.contains(&conn.username)) {
+            conn.send(&msg_out);
+        }
+        let remote_usernames = self.fanout.remote_usernames();
+        for author in authors.into_iter().filter(|author| remote_usernames.contains(author)) {
+            self.fanout.publish(Event::Direct { username: author, message: msg_out.clone() });
+        }
+        Ok(())
+    }
+
+    /// Sends a message and all replies to it to the requesting user.
+    pub async fn send_thread(&self, user_id: usize, message_id: u64) -> Result<(), ChatError> {
+        let parent = self.load_message(message_id).await?;
+        if parent.kind != EventKind::Message {
+            return Err(ChatError::new(ErrorCode::NotFound, format!("Message {} has no thread", message_id)));
+        }
+        let store = self.store.clone();
+        let replies = storage::run_blocking(move || store.replies(message_id)).await.map_err(|err| {
+            log::warn!("Cannot load replies to message {}: {}", message_id, err);
+            internal_error()
+        })?;
+        let mut events = vec![parent];
+        events.extend(replies);
+        let thread = self.with_reactions(events).await;
+
+        let conns = self.connections.lock().await;
+        if let Some(user_conn) = conns.get(&user_id) {
+            user_conn.send(&ServerMessage::Thread { room: self.name.clone(), message_id, messages: thread });
+        }
+        Ok(())
+    }
+
+    /// Applies `change` to a stored message of this room if `user_id` owns
+    /// it, then broadcasts the updated message to the room. Messages stored
+    /// without an owner cannot be changed.
+    async fn update_message<F>(&self, user_id: usize, message_id: u64, change: F) -> Result<(), ChatError>
+    where
+        F: FnOnce(&mut ChatMessage),
+    {
+        let owner = connection_of(&*self.connections.lock().await, user_id)?.owner.clone();
+        let _sequence = self.sequence.lock().await;
+        let mut event = self.load_live_message(message_id).await?;
+        if event.owner != Some(owner) {
+            return Err(ChatError::new(ErrorCode::Forbidden, "You can only change your own messages"));
+        }
+
+        change(&mut event.message);
+        let (store, stored) = (self.store.clone(), event.clone());
+        storage::run_blocking(move || store.update(&stored)).await.map_err(|err| {
+            log::warn!("Cannot update message {}: {}", message_id, err);
+            internal_error()
+        })?;
+
+        let message = match self.with_reactions(vec![event]).await.pop() {
+            Some(message) => message,
+            None => return Ok(()),
         };
+        let msg_out = ServerMessage::updated(self.name.clone(), message);
+        self.publish(&msg_out);
+        self.broadcast(&msg_out).await;
+        Ok(())
+    }
 
-        let mut conns = self.connections.lock().await;
-        if chat_msg.author.is_empty() {
-            let user_conn = match conns.get(&user_id) {
-                Some(conn) => conn,
-                _ => {
-                    log::warn!("Cannot find a user {}", user_id);
-                    return;
+    pub async fn edit_message(&self, user_id: usize, message_id: u64, text: String) -> Result<(), ChatError> {
+        self.update_message(user_id, message_id, |msg| msg.edit(text)).await
+    }
+
+    pub async fn delete_message(&self, user_id: usize, message_id: u64) -> Result<(), ChatError> {
+        self.update_message(user_id, message_id, |msg| msg.delete()).await
+    }
+
+    /// Adds or removes the user's `emoji` reaction to a message and
+    /// broadcasts the resulting reactions of that message.
+    pub async fn react(&self, user_id: usize, message_id: u64, emoji: String, add: bool) -> Result<(), ChatError> {
+        if emoji.is_empty() || emoji.chars().count() > MAX_EMOJI_LEN {
+            return Err(ChatError::new(ErrorCode::InvalidRequest, "Invalid reaction"));
+        }
+        let username = username_of(&*self.connections.lock().await, user_id)?;
+        self.load_live_message(message_id).await?;
+
+        let _sequence = self.sequence.lock().await;
+        let (store, reaction, reactor) = (self.store.clone(), emoji.clone(), username.clone());
+        let changed = storage::run_blocking(move || {
+            let changed = if add {
+                store.add_reaction(message_id, &reaction, &reactor)?
+            } else {
+                store.remove_reaction(message_id, &reaction, &reactor)?
+            };
+            Ok((changed, store.reactions(&[message_id])?))
+        }).await;
+        let current = match changed {
+            Ok((false, _)) => return Ok(()),
+            Ok((true, mut reactions)) => reactions.remove(&message_id).map(|r| to_reactions(&r)).unwrap_or_default(),
+            Err(err) => {
+                log::warn!("Cannot store reaction to message {}: {}", message_id, err);
+                return Err(internal_error());
+            }
+        };
+
+        let room = self.name.clone();
+        let msg_out = if add {
+            ServerMessage::AddReaction { room, message_id, emoji, username, reactions: current }
+        } else {
+            ServerMessage::RemoveReaction { room, message_id, emoji, username, reactions: current }
+        };
+        self.publish(&msg_out);
+        self.broadcast(&msg_out).await;
+        Ok(())
+    }
+
+    /// Tells other users that `user_id` started or stopped typing. A start
+    /// that is not refreshed within the configured timeout expires.
+    pub async fn set_typing(self: &Arc<Self>, user_id: usize, is_typing: bool) {
+        let was_typing = {
+            let mut typing = self.typing.lock().await;
+            if is_typing {
+                typing.insert(user_id, Instant::now()).is_some()
+            } else {
+                typing.remove(&user_id).is_some()
+            }
+        };
+        if is_typing == was_typing {
+            return;
+        }
+
+        // Refreshes only move the timestamp, the task started with the
+        // typing watches it until it expires.
+        if is_typing {
+            let room = self.clone();
+            tokio::spawn(async move { room.expire_typing(user_id).await });
+        }
+        self.broadcast_typing(user_id, is_typing).await;
+    }
+
+    /// Waits until the user's typing was not refreshed for the timeout.
+    /// Returns early if they stopped typing in the meantime.
+    async fn expire_typing(&self, user_id: usize) {
+        let timeout = Duration::from_secs(self.config.typing_timeout);
+        loop {
+            let deadline = {
+                let mut typing = self.typing.lock().await;
+                match typing.get(&user_id) {
+                    Some(since) if since.elapsed() >= timeout => {
+                        typing.remove(&user_id);
+                        break;
+                    },
+                    Some(since) => *since + timeout,
+                    None => return,
                 }
             };
-            chat_msg.author = user_conn.username.clone();
+            tokio::time::sleep_until(deadline).await;
         }
+        self.broadcast_typing(user_id, false).await;
+    }
 
-        let msg_out = WebSocketMessage::from_chat_msg(chat_msg).to_string();
-        for (_id, conn) in conns.iter_mut() {
-            let _ = conn.sink.send(Message::Text(msg_out.clone())).await;
+    async fn broadcast_typing(&self, user_id: usize, is_typing: bool) {
+        let conns = self.connections.lock().await;
+        let username = match conns.get(&user_id) {
+            Some(conn) => conn.username.clone(),
+            _ => return,
+        };
+        let msg_out = ServerMessage::Typing { room: self.name.clone(), username, typing: is_typing };
+        self.publish(&msg_out);
+        for (_, conn) in conns.iter().filter(|(id, _)| **id != user_id) {
+            conn.send(&msg_out);
         }
     }
 
     pub async fn broadcast_users_list(&self) {
-        let mut conns = self.connections.lock().await;
-        let users: Vec<String> = conns.iter().map(|(_, conn)| conn.username.clone()).collect();
-
-        let msg_out = WebSocketMessage::from_users_list(users).to_string();
-        for (_id, conn) in conns.iter_mut() {
-            let _ = conn.sink.send(Message::Text(msg_out.clone())).await;
-        }
-    }
-
-    pub async fn handle_chat_msg(&self, user_id: usize, msg: String) {
-        if let Some(new_msg) = self.parse_message(msg.clone()) {
-            match new_msg.message_type {
-                WebSocketMessageType::NewMessage => {
-                    self.broadcast_message(new_msg, user_id).await;
-                },
-                WebSocketMessageType::UsernameChange => {
-                    if let Some(new_username) = new_msg.username {
-                        self.change_username(user_id, new_username.clone()).await;
-                    } else {
-                        log::warn!("New username is empty");
-                    }
-
-                },
-                WebSocketMessageType::UserList => {
-                    self.broadcast_users_list().await;
-                },
-                WebSocketMessageType::System => {
-                    log::debug!("not implemented");
-                },
+        let conns = self.connections.lock().await;
+        let msg_out = self.users_list_msg(&conns);
+        for conn in conns.values() {
+            conn.send(&msg_out);
+        }
+    }
+
+    /// Tells the room the server is going down.
+    pub async fn announce_shutdown(&self) {
+        let _sequence = self.sequence.lock().await;
+        let msg_out = self.system_msg("Server is restarting".to_string()).await;
+        self.broadcast(&msg_out).await;
+    }
+
+    /// Removes the user without telling the others, returning their name.
+    pub async fn remove(&self, user_id: usize) -> Option<String> {
+        self.typing.lock().await.remove(&user_id);
+        match self.connections.lock().await.remove(&user_id) {
+            Some(conn) => Some(conn.username),
+            None => {
+                log::warn!("Cannot find a user {} to remove", user_id);
+                None
             }
-        } else {
-            self.broadcast(Message::Text(msg)).await;
         }
     }
 
@@ -192,7 +592,50 @@ impl ChatRoom {
             user_conn.username
         };
 
+        if self.typing.lock().await.remove(&user_id).is_some() {
+            let msg = ServerMessage::Typing { room: self.name.clone(), username: username.clone(), typing: false };
+            self.publish(&msg);
+            self.broadcast(&msg).await;
+        }
         self.update_status(username, UserStatus::Left).await;
         self.broadcast_users_list().await;
     }
 }
+
+fn internal_error() -> ChatError {
+    ChatError::new(ErrorCode::Internal, "Something went wrong, try again later")
+}
+
+/// Presence by username, for user lists. A user connected more than once
+/// is as present as their most present connection; users who are simply
+/// online are left out.
+pub fn merge_presence(users: impl IntoIterator<Item = (String, Presence)>) -> BTreeMap<String, Presence> {
+    let mut merged: BTreeMap<String, Presence> = BTreeMap::new();
+    for (username, presence) in users {
+        match merged.get(&username) {
+            Some(current) if current.status <= presence.status => {},
+            _ => {
+                merged.insert(username, presence);
+            },
+        }
+    }
+    merged.retain(|_, presence| *presence != Presence::default());
+    merged
+}
+
+fn connection_of(conns: &HashMap<usize, ChatRoomConnection>, user_id: usize) -> Result<&ChatRoomConnection, ChatError> {
+    conns.get(&user_id).ok_or_else(|| ChatError::new(ErrorCode::NotInRoom, "You are not in this room"))
+}
+
+fn username_of(conns: &HashMap<usize, ChatRoomConnection>, user_id: usize) -> Result<String, ChatError> {
+    connection_of(conns, user_id).map(|conn| conn.username.clone())
+}
+
+fn to_reactions(reactions: &MessageReactions) -> Vec<Reaction> {
+    reactions.iter()
+        .map(|(emoji, users)| Reaction {
+            emoji: emoji.clone(),
+            users: users.iter().cloned().collect(),
+        })
+        .collect()
+}
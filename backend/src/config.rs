@@ -0,0 +1,193 @@
+use std::net::IpAddr;
+
+use rocket::serde::Deserialize;
+
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum StorageKind {
+    Memory,
+    Sqlite,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum AuthKind {
+    /// Anyone can connect under an anonymous name, logging in is optional.
+    None,
+    /// Only account sessions.
+    Accounts,
+    /// Static tokens listed in `auth_tokens_path`.
+    Tokens,
+    /// JWTs signed with `jwt_secret`.
+    Jwt,
+}
+
+impl AuthKind {
+    /// Whether account sessions are accepted, and so whether the
+    /// `/accounts` endpoints are served.
+    pub fn uses_accounts(&self) -> bool {
+        matches!(self, AuthKind::None | AuthKind::Accounts)
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum BusKind {
+    /// A single instance, nothing leaves the process.
+    Memory,
+    /// Instances sharing a redis pub/sub channel.
+    Redis,
+}
+
+/// What happens to a connection whose outbound queue is full.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum SlowConsumerPolicy {
+    /// Drop its oldest queued message to make room.
+    DropOldest,
+    /// Tell the client why and close the connection.
+    Disconnect,
+}
+
+/// Token bucket: up to `burst` requests at once, refilled at `per_minute`.
+/// A `burst` of `0` disables the limit.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct RateLimit {
+    pub burst: u32,
+    pub per_minute: u32,
+}
+
+/// Chat settings, read from the same figment as Rocket's own config
+/// (`Rocket.toml` or `ROCKET_*` environment variables).
+#[derive(Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde", default)]
+pub struct ChatConfig {
+    /// Where messages are persisted.
+    pub storage: StorageKind,
+    /// Path of the SQLite database file.
+    pub database_path: String,
+    /// Messages kept per room by the in-memory store.
+    pub memory_capacity: usize,
+    /// Latest messages sent to a user joining a room.
+    pub history_size: usize,
+    /// Seconds after which a typing indicator that was not refreshed expires.
+    pub typing_timeout: u64,
+    /// How websocket connections are authenticated.
+    pub auth: AuthKind,
+    /// File with `<token> <username>` lines, used by `tokens` auth.
+    pub auth_tokens_path: String,
+    /// HMAC key of `jwt` auth.
+    pub jwt_secret: String,
+    /// Seconds a login session stays valid.
+    pub session_lifetime: u64,
+    /// Registrations and logins from one IP address. Each hashes a
+    /// password, which takes time and memory on purpose.
+    pub account_limit: RateLimit,
+    /// Oldest protocol version accepted. `1` admits clients without `Hello`.
+    pub min_protocol_version: u32,
+    /// Milliseconds to wait for a client's `Hello` before treating it as
+    /// a legacy client.
+    pub hello_timeout_ms: u64,
+    /// Messages queued for a connection that its socket has not taken yet.
+    pub outbound_queue_size: usize,
+    /// What to do when a connection's outbound queue is full.
+    pub slow_consumer: SlowConsumerPolicy,
+    /// Milliseconds a socket may take to accept one message before the
+    /// connection is dropped as stalled.
+    pub send_timeout_ms: u64,
+    /// Chat, direct, edited and deleted messages of one connection.
+    pub message_limit: RateLimit,
+    /// Username changes of one connection.
+    pub username_limit: RateLimit,
+    /// User and room list requests of one connection.
+    pub user_list_limit: RateLimit,
+    /// Presence changes of one connection.
+    pub presence_limit: RateLimit,
+    /// Typing notices of one connection.
+    pub typing_limit: RateLimit,
+    /// Reactions added or removed by one connection.
+    pub reaction_limit: RateLimit,
+    /// Read markers of one connection.
+    pub read_limit: RateLimit,
+    /// Rooms joined or left by one connection.
+    pub room_limit: RateLimit,
+    /// Thread requests of one connection.
+    pub thread_limit: RateLimit,
+    /// All of the above from one IP address together.
+    pub ip_limit: RateLimit,
+    /// Rate limit violations within a minute after which a user is muted.
+    pub mute_after: u32,
+    /// Seconds a muted user cannot send any of the limited requests.
+    pub mute_seconds: u64,
+    /// Largest websocket frame or message a client may send, in bytes.
+    pub max_frame_size: usize,
+    /// Longest chat or direct message, in characters.
+    pub max_message_length: usize,
+    /// Seconds between pings to every connection, `0` turns them off.
+    pub ping_interval: u64,
+    /// Pings in a row a connection may leave unanswered before it is closed.
+    pub missed_pings: u32,
+    /// How instances share broadcasts and who is connected.
+    pub bus: BusKind,
+    /// Server of `redis` bus.
+    pub redis_url: String,
+    /// Pub/sub channel of `redis` bus, the same for every instance.
+    pub redis_channel: String,
+    /// Seconds between announcements of who is connected to an instance.
+    /// Users of an instance silent for three of them are dropped.
+    pub bus_heartbeat: u64,
+    /// Reverse proxies whose `ip_header` is trusted to carry the client's
+    /// address. Anyone else is known by the address they connect from.
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Usernames that may kick, ban and mute users once authenticated.
+    pub moderators: Vec<String>,
+    /// Usernames that may also moderate moderators once authenticated.
+    pub admins: Vec<String>,
+}
+
+impl Default for ChatConfig {
+    fn default() -> ChatConfig {
+        ChatConfig {
+            storage: StorageKind::Sqlite,
+            database_path: "chat.db".to_string(),
+            memory_capacity: 1000,
+            history_size: 50,
+            typing_timeout: 5,
+            auth: AuthKind::None,
+            auth_tokens_path: "tokens.txt".to_string(),
+            jwt_secret: String::new(),
+            session_lifetime: 7 * 24 * 60 * 60,
+            account_limit: RateLimit { burst: 5, per_minute: 10 },
+            min_protocol_version: 1,
+            hello_timeout_ms: 1000,
+            outbound_queue_size: 256,
+            slow_consumer: SlowConsumerPolicy::Disconnect,
+            send_timeout_ms: 10_000,
+            message_limit: RateLimit { burst: 10, per_minute: 60 },
+            username_limit: RateLimit { burst: 3, per_minute: 6 },
+            user_list_limit: RateLimit { burst: 5, per_minute: 30 },
+            presence_limit: RateLimit { burst: 5, per_minute: 30 },
+            typing_limit: RateLimit { burst: 5, per_minute: 60 },
+            reaction_limit: RateLimit { burst: 10, per_minute: 60 },
+            read_limit: RateLimit { burst: 10, per_minute: 60 },
+            room_limit: RateLimit { burst: 10, per_minute: 30 },
+            thread_limit: RateLimit { burst: 5, per_minute: 30 },
+            ip_limit: RateLimit { burst: 50, per_minute: 300 },
+            mute_after: 10,
+            mute_seconds: 60,
+            max_frame_size: 64 * 1024,
+            max_message_length: 2000,
+            ping_interval: 30,
+            missed_pings: 3,
+            bus: BusKind::Memory,
+            redis_url: "redis://127.0.0.1/".to_string(),
+            redis_channel: "chat".to_string(),
+            bus_heartbeat: 10,
+            trusted_proxies: Vec::new(),
+            moderators: Vec::new(),
+            admins: Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,387 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use rocket::{
+    futures::StreamExt,
+    tokio::{
+        self,
+        sync::mpsc::{self, error::TrySendError},
+        time::{interval, sleep, Instant},
+    },
+};
+
+use common::Presence;
+
+use crate::bus::{Bus, Envelope, Event, Members};
+use crate::config::ChatConfig;
+use crate::rooms::ChatRooms;
+
+/// Events waiting to be published before new ones are dropped.
+const OUTGOING_CAPACITY: usize = 4096;
+/// Heartbeats an instance may miss before its users are dropped.
+const MISSED_HEARTBEATS: u32 = 3;
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+
+struct Remote {
+    members: Members,
+    seen: Instant,
+}
+
+/// This instance's side of the bus: publishes what its rooms do and
+/// keeps track of who is connected to the other instances.
+pub struct FanOut {
+    instance: String,
+    outgoing: mpsc::Sender<Event>,
+    remotes: Mutex<HashMap<String, Remote>>,
+    heartbeat: Duration,
+}
+
+impl FanOut {
+    /// Creates the fan-out and the queue of events to hand to `start`.
+    pub fn new(config: &ChatConfig) -> (FanOut, mpsc::Receiver<Event>) {
+        let (outgoing, events) = mpsc::channel(OUTGOING_CAPACITY);
+        let fanout = FanOut {
+            instance: format!("{:016x}", rand::random::<u64>()),
+            outgoing,
+            remotes: Mutex::default(),
+            heartbeat: Duration::from_secs(config.bus_heartbeat.max(1)),
+        };
+        (fanout, events)
+    }
+
+    /// Short tag of this instance, telling apart names that every
+    /// instance hands out alike.
+    pub fn tag(&self) -> &str {
+        &self.instance[..6]
+    }
+
+    fn remotes(&self) -> MutexGuard<'_, HashMap<String, Remote>> {
+        self.remotes.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Queues the event for the other instances without waiting.
+    pub fn publish(&self, event: Event) {
+        match self.outgoing.try_send(event) {
+            Ok(()) => {},
+            Err(TrySendError::Full(_)) => log::warn!("Bus is not keeping up, dropping an event"),
+            Err(TrySendError::Closed(_)) => log::warn!("Bus is gone, dropping an event"),
+        }
+    }
+
+    /// Users of a room connected to other instances, with their presence.
+    pub fn remote_users(&self, room: &str) -> Vec<(String, Presence)> {
+        self.remotes().values()
+            .flat_map(|remote| {
+                let users = remote.members.rooms.get(room).into_iter().flatten();
+                users.map(|user| (user.clone(), remote.members.presence_of(user)))
+            })
+            .collect()
+    }
+
+    /// Every user connected to other instances.
+    pub fn remote_usernames(&self) -> Vec<String> {
+        self.remotes().values()
+            .flat_map(|remote| remote.members.users.iter().cloned())
+            .collect()
+    }
+
+    /// Rooms a user joined on other instances.
+    pub fn remote_rooms_of(&self, username: &str) -> BTreeSet<String> {
+        self.remotes().values()
+            .flat_map(|remote| remote.members.rooms.iter())
+            .filter(|(_, users)| users.iter().any(|user| user == username))
+            .map(|(room, _)| room.clone())
+            .collect()
+    }
+
+    pub fn remote_rooms(&self) -> BTreeSet<String> {
+        self.remotes().values()
+            .flat_map(|remote| remote.members.rooms.keys().cloned())
+            .collect()
+    }
+
+    /// Stores who is connected to an instance. Returns the rooms whose
+    /// users changed.
+    fn update(&self, instance: String, members: Members) -> BTreeSet<String> {
+        let mut remotes = self.remotes();
+        let changed = match remotes.get(&instance) {
+            Some(old) => old.members.changed_rooms(&members),
+            None => members.changed_rooms(&Members::default()),
+        };
+        remotes.insert(instance, Remote { members, seen: Instant::now() });
+        changed
+    }
+
+    fn remove(&self, instance: &str) -> BTreeSet<String> {
+        match self.remotes().remove(instance) {
+            Some(old) => old.members.changed_rooms(&Members::default()),
+            None => BTreeSet::new(),
+        }
+    }
+
+    /// Forgets instances that stopped sending heartbeats. Returns the
+    /// rooms whose users changed.
+    fn expire(&self) -> BTreeSet<String> {
+        let timeout = self.heartbeat * MISSED_HEARTBEATS;
+        let mut changed = BTreeSet::new();
+        self.remotes().retain(|instance, remote| {
+            if remote.seen.elapsed() < timeout {
+                return true;
+            }
+            log::warn!("Instance {} stopped sending heartbeats, dropping its users", instance);
+            changed.extend(remote.members.changed_rooms(&Members::default()));
+            false
+        });
+        changed
+    }
+}
+
+/// Spawns the tasks connecting the rooms to the bus: one publishing the
+/// queued events, one handling what other instances publish and one
+/// sending heartbeats and dropping silent instances.
+pub fn start(rooms: Arc<ChatRooms>, bus: Arc<dyn Bus>, events: mpsc::Receiver<Event>) {
+    let instance = rooms.fanout().instance.clone();
+    tokio::spawn(publish(bus.clone(), instance, events));
+    tokio::spawn(subscribe(rooms.clone(), bus));
+    tokio::spawn(heartbeat(rooms));
+}
+
+async fn publish(bus: Arc<dyn Bus>, origin: String, mut events: mpsc::Receiver<Event>) {
+    while let Some(event) = events.recv().await {
+        let envelope = Envelope {
+            origin: origin.clone(),
+            event,
+        };
+        if let Err(err) = bus.publish(&envelope).await {
+            log::warn!("Cannot publish to the bus: {}", err);
+        }
+    }
+}
+
+async fn subscribe(rooms: Arc<ChatRooms>, bus: Arc<dyn Bus>) {
+    loop {
+        match bus.subscribe().await {
+            Ok(mut envelopes) => {
+                // Whatever happened while not listening is asked for again.
+                rooms.fanout().publish(Event::Sync);
+                while let Some(envelope) = envelopes.next().await {
+                    if envelope.origin != rooms.fanout().instance {
+                        handle(&rooms, envelope).await;
+                    }
+                }
+                log::warn!("Lost the bus subscription, resubscribing");
+            },
+            Err(err) => log::warn!("Cannot subscribe to the bus: {}", err),
+        }
+        sleep(RESUBSCRIBE_DELAY).await;
+    }
+}
+
+async fn handle(rooms: &ChatRooms, envelope: Envelope) {
+    let fanout = rooms.fanout();
+    match envelope.event {
+        Event::Room { room, message } => rooms.deliver_to_room(&room, message).await,
+        Event::Direct { username, message } => rooms.deliver_to_user(&username, &message).await,
+        Event::Members { members } => {
+            let changed = fanout.update(envelope.origin, members);
+            rooms.refresh_user_lists(changed).await;
+        },
+        Event::Sync => rooms.publish_members().await,
+        Event::Claim { username } => rooms.claim_username(&username).await,
+        Event::Moderate { username, sanction, by, reason } => {
+            rooms.apply_sanction(&username, &sanction, &by, reason.as_deref()).await;
+        },
+        Event::BanAddress { ip, by, reason } => rooms.close_address(ip, &by, reason.as_deref()).await,
+        Event::Gone => {
+            log::info!("Instance {} is shutting down", envelope.origin);
+            let changed = fanout.remove(&envelope.origin);
+            rooms.refresh_user_lists(changed).await;
+        },
+    }
+}
+
+async fn heartbeat(rooms: Arc<ChatRooms>) {
+    let mut ticks = interval(rooms.fanout().heartbeat);
+    loop {
+        ticks.tick().await;
+        rooms.publish_members().await;
+        let changed = rooms.fanout().expire();
+        rooms.refresh_user_lists(changed).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+
+    use rocket_ws::Message;
+
+    use common::{ServerMessage, WireFormat, PROTOCOL_VERSION};
+
+    use crate::auth::Identity;
+    use crate::bus::{MemoryBus, RedisBus};
+    use crate::config::BusKind;
+    use crate::outbox::Outbox;
+    use crate::protocol::ClientProtocol;
+    use crate::rooms::{ChatSession, DEFAULT_ROOM};
+    use crate::storage::MemoryStore;
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// An instance on `bus`, sharing `store` with the others like
+    /// instances behind one load balancer do.
+    fn instance(config: &Arc<ChatConfig>, store: &Arc<MemoryStore>, bus: Arc<dyn Bus>) -> Arc<ChatRooms> {
+        let (fanout, events) = FanOut::new(config);
+        let rooms = Arc::new(ChatRooms::new(store.clone(), store.clone(), store.clone(), Arc::new(fanout), config.clone()));
+        start(rooms.clone(), bus, events);
+        rooms
+    }
+
+    /// Connects a current client without a socket to the default room.
+    async fn connect(rooms: &ChatRooms, user_id: usize, identity: Option<Identity>) -> ChatSession {
+        let outbox = Outbox::detached(user_id, &ChatConfig::default());
+        let mut session = rooms.open_session(user_id, outbox, DEFAULT_ROOM.to_string(), identity, None, WireFormat::Json);
+        let protocol = ClientProtocol::negotiate(PROTOCOL_VERSION, &[], PROTOCOL_VERSION, WireFormat::Json).unwrap();
+        session.protocol = Arc::new(protocol);
+        rooms.connect(&mut session).await;
+        session
+    }
+
+    /// Everything queued for the session so far.
+    fn received(session: &ChatSession) -> Vec<ServerMessage> {
+        session.outbox.queued().iter()
+            .filter_map(|frame| match frame {
+                Message::Text(text) => Some(ServerMessage::decode(text).unwrap()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Texts of the room messages the session received.
+    fn messages(session: &ChatSession) -> Vec<String> {
+        received(session).into_iter()
+            .filter_map(|msg| match msg {
+                ServerMessage::NewMessage { message, .. } => Some(message.message),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Waits until `condition` holds, failing the test after `TIMEOUT`.
+    async fn eventually<F, Fut>(what: &str, mut condition: F)
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = bool>,
+    {
+        let deadline = Instant::now() + TIMEOUT;
+        while !condition().await {
+            assert!(Instant::now() < deadline, "timed out waiting until {}", what);
+            sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    /// Needs a redis server at `REDIS_URL`, `redis://127.0.0.1/` by default:
+    /// `cargo test -p backend -- --ignored`.
+    #[rocket::async_test]
+    #[ignore = "needs a redis server"]
+    async fn instances_share_members_over_redis() {
+        let config = Arc::new(ChatConfig {
+            bus: BusKind::Redis,
+            redis_url: std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".to_string()),
+            redis_channel: format!("chat-test-{:016x}", rand::random::<u64>()),
+            ..ChatConfig::default()
+        });
+        let store = Arc::new(MemoryStore::new(100));
+        let redis = || RedisBus::open(&config.redis_url, &config.redis_channel).expect("Cannot open redis bus");
+        let first = instance(&config, &store, Arc::new(redis()));
+        let second = instance(&config, &store, Arc::new(redis()));
+
+        // Both announce themselves on start, which also shows they subscribed.
+        eventually("the instances see each other", || async {
+            first.fanout().remotes().contains_key(&second.fanout().instance)
+                && second.fanout().remotes().contains_key(&first.fanout().instance)
+        }).await;
+
+        let members = Members {
+            users: vec!["alice".to_string()],
+            rooms: [("general".to_string(), vec!["alice".to_string()])].into(),
+            ..Members::default()
+        };
+        first.fanout().publish(Event::Members { members });
+        eventually("the second instance sees alice", || async {
+            second.fanout().remote_usernames() == ["alice"]
+        }).await;
+        assert_eq!(second.fanout().remote_rooms_of("alice"), ["general".to_string()].into());
+        assert_eq!(second.list().await, ["general"]);
+
+        first.fanout().publish(Event::Gone);
+        eventually("the second instance drops alice", || async {
+            second.fanout().remote_usernames().is_empty()
+        }).await;
+        assert_ne!(first.fanout().tag(), second.fanout().tag());
+    }
+
+    #[rocket::async_test]
+    async fn instances_share_rooms_over_one_bus() {
+        // Tagged names as on redis, over a bus both instances share.
+        let config = Arc::new(ChatConfig { bus: BusKind::Redis, ..ChatConfig::default() });
+        let store = Arc::new(MemoryStore::new(100));
+        let bus: Arc<dyn Bus> = Arc::new(MemoryBus::new());
+        let first = instance(&config, &store, bus.clone());
+        let second = instance(&config, &store, bus);
+        eventually("the instances see each other", || async {
+            first.fanout().remotes().contains_key(&second.fanout().instance)
+                && second.fanout().remotes().contains_key(&first.fanout().instance)
+        }).await;
+
+        // User ids are counted per instance, the tag keeps the guests apart.
+        let first_guest = connect(&first, 1, None).await;
+        let second_guest = connect(&second, 1, None).await;
+        assert_eq!(first_guest.username, format!("user #1-{}", first.fanout().tag()));
+        assert_eq!(second_guest.username, format!("user #1-{}", second.fanout().tag()));
+
+        let alice = || Some(Identity { username: "alice".to_string() });
+        let first_alice = connect(&first, 2, alice()).await;
+        let second_alice = connect(&second, 2, alice()).await;
+        eventually("the second instance sees both on the first", || async {
+            let mut remote = second.fanout().remote_usernames();
+            remote.sort();
+            remote == ["alice", first_guest.username.as_str()]
+        }).await;
+
+        // Alice is connected to both, but listed once.
+        let room = second.get(DEFAULT_ROOM).await.unwrap();
+        room.broadcast_users_list().await;
+        let lists: Vec<Vec<String>> = received(&second_guest).into_iter()
+            .filter_map(|msg| match msg {
+                ServerMessage::UserList { users, .. } => Some(users),
+                _ => None,
+            })
+            .collect();
+        let mut expected = vec!["alice".to_string(), first_guest.username.clone(), second_guest.username.clone()];
+        expected.sort();
+        assert_eq!(lists.last(), Some(&expected));
+
+        first.get(DEFAULT_ROOM).await.unwrap()
+            .broadcast_message("hello".to_string(), None, first_guest.user_id).await.unwrap();
+        eventually("the message reaches the second instance", || async {
+            messages(&second_guest) == ["hello"] && messages(&second_alice) == ["hello"]
+        }).await;
+        second.get(DEFAULT_ROOM).await.unwrap()
+            .broadcast_message("hi".to_string(), None, second_guest.user_id).await.unwrap();
+        // The bus keeps the order, so the first instance would have had its
+        // own message back by now if it did not skip it.
+        eventually("the answer reaches the first instance", || async {
+            messages(&first_guest).len() == 2
+        }).await;
+        assert_eq!(messages(&first_guest), ["hello", "hi"]);
+        assert_eq!(messages(&first_alice), ["hello", "hi"]);
+        assert_eq!(messages(&second_alice), ["hello", "hi"]);
+    }
+}
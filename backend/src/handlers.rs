@@ -1,47 +1,223 @@
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{
+    future::pending,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
 use rocket::{
-    futures::StreamExt, 
-    State
+    futures::{stream::SplitStream, StreamExt},
+    http::Status,
+    serde::json::Json,
+    tokio::{self, time::{interval_at, timeout, Instant, Interval}},
+    Shutdown, State
 };
-use rocket_ws::{Channel, Message, WebSocket};
-use log;
+use rocket_ws::{frame::CloseCode, result::Error as WsError, stream::DuplexStream, Message, WebSocket};
+use serde::Deserialize;
+
+use common::ChatMessage;
 
-use crate::chat::ChatRoom;
+use crate::accounts::{AccountError, Accounts, Session};
+use crate::auth::{Authenticated, BearerToken};
+use crate::limits::ClientAddr;
+use crate::protocol::{Subprotocol, Upgrade};
+use crate::rooms::{ChatRooms, DEFAULT_ROOM, SHUTDOWN_REASON};
 use crate::metrics::{WS_NEW_CONNECTIONS_TOTAL, WS_CONNECTIONS_TOTAL};
 
 static USER_ID_COUNTER: AtomicUsize = AtomicUsize::new(1);
+const HISTORY_DEFAULT_LIMIT: usize = 50;
+const HISTORY_MAX_LIMIT: usize = 1000;
 
 
 #[rocket::get("/")]
-pub fn chat<'r>(ws: WebSocket, state: &'r State<ChatRoom>) -> Channel<'r> {
-    ws.channel(move |stream| Box::pin(async move {
+pub fn chat<'r>(
+    ws: WebSocket,
+    subprotocol: Subprotocol,
+    user: Authenticated,
+    addr: ClientAddr,
+    shutdown: Shutdown,
+    state: &'r State<Arc<ChatRooms>>,
+) -> Upgrade<'r> {
+    chat_room(DEFAULT_ROOM, ws, subprotocol, user, addr, shutdown, state)
+}
+
+#[rocket::get("/ws/<room>")]
+pub fn chat_room<'r>(
+    room: &str,
+    ws: WebSocket,
+    subprotocol: Subprotocol,
+    user: Authenticated,
+    addr: ClientAddr,
+    mut shutdown: Shutdown,
+    state: &'r State<Arc<ChatRooms>>,
+) -> Upgrade<'r> {
+    let room = room.to_string();
+    let ip = addr.0;
+    let format = subprotocol.0.unwrap_or_default();
+    let channel = ws.config(state.ws_config()).channel(move |stream| Box::pin(async move {
         let user_id = USER_ID_COUNTER.fetch_add(1, Ordering::Relaxed);
         let (ws_sink, mut ws_stream) = stream.split();
+        let (outbox, mut writer) = state.open_outbox(user_id, ws_sink);
+        let mut session = state.open_session(user_id, outbox.clone(), room, user.0, ip, format);
 
-        state.add(user_id, ws_sink).await;
+        // Clients that do not open with a Hello in time speak the legacy protocol.
+        let first_msg = match timeout(state.hello_timeout(), next_data(&mut ws_stream)).await {
+            Ok(Some(msg)) => Some(msg),
+            Ok(None) => {
+                outbox.close();
+                return Ok(());
+            },
+            Err(_) => None,
+        };
+        let admitted = match state.handshake(&mut session, first_msg).await {
+            Ok(first_msg) => state.admit(&mut session).await.map(|()| first_msg),
+            Err(err) => Err(err),
+        };
+        let first_msg = match admitted {
+            Ok(first_msg) => first_msg,
+            Err(err) => {
+                log::warn!("Rejecting user {}: {}", user_id, err);
+                session.send_error(err.clone(), None);
+                session.close(CloseCode::Policy, &err.text);
+                let _ = writer.await;
+                return Ok(());
+            }
+        };
+
+        state.connect(&mut session).await;
         WS_NEW_CONNECTIONS_TOTAL.inc();
         WS_CONNECTIONS_TOTAL.inc();
-    
-        while let Some(msg) = ws_stream.next().await {
-            if let Ok(msg_content) = msg {
-                match msg_content {
-                    Message::Text(json_msg) => {
-                        state.handle_chat_msg(user_id, json_msg).await;
-                    },
-                    Message::Ping(_) => {},
-                    Message::Pong(_) => {},
-                    _ => {
-                        // Unsupported
-                        log::warn!("Unsupported message type {}", msg_content);
+        if let Some(msg) = first_msg {
+            state.handle_chat_msg(&mut session, msg).await;
+        }
 
+        // The writer ends early when it gives up on the socket, and the
+        // heartbeat when the client stops answering pings; the connection
+        // is dropped then instead of waiting for the client. On shutdown
+        // the queued messages are still written, within Rocket's
+        // `shutdown.grace` after which its I/O is cut.
+        let mut pings = state.ping_interval()
+            .map(|period| interval_at(Instant::now() + period, period));
+        let mut writer_done = false;
+        loop {
+            let msg = tokio::select! {
+                msg = ws_stream.next() => msg,
+                _ = &mut writer => {
+                    writer_done = true;
+                    break;
+                },
+                _ = &mut shutdown => {
+                    state.shutdown().await;
+                    session.close(CloseCode::Restart, SHUTDOWN_REASON);
+                    break;
+                },
+                _ = next_ping(&mut pings) => {
+                    if !state.heartbeat(&session) {
+                        break;
                     }
+                    continue;
+                },
+            };
+            session.last_seen = Instant::now();
+            let msg_content = match msg {
+                Some(Ok(msg_content)) => msg_content,
+                Some(Err(WsError::Capacity(err))) => {
+                    log::warn!("Closing connection of user {}: {}", user_id, err);
+                    session.close(CloseCode::Size, "Message is too big");
+                    break;
+                },
+                Some(Err(_)) => continue,
+                None => break,
+            };
+            match msg_content {
+                Message::Text(_) | Message::Binary(_) => {
+                    state.handle_chat_msg(&mut session, msg_content).await;
+                },
+                Message::Ping(_) => {},
+                Message::Pong(_) => {},
+                _ => {
+                    // Unsupported
+                    log::warn!("Unsupported message type {}", msg_content);
+
                 }
             }
         }
-        state.flush(user_id).await;
+        state.disconnect(&mut session).await;
         WS_CONNECTIONS_TOTAL.dec();
-    
+        if !writer_done {
+            outbox.close();
+            let _ = writer.await;
+        }
+
         Ok(())
-    }))
+    }));
+    Upgrade { channel, subprotocol }
+}
+
+/// Waits for the next tick of the ping interval, forever if there is none.
+async fn next_ping(pings: &mut Option<Interval>) {
+    match pings {
+        Some(pings) => {
+            pings.tick().await;
+        },
+        None => pending().await,
+    }
+}
+
+/// Waits for the next text or binary message, skipping control frames.
+/// `None` once the client is gone.
+async fn next_data(ws_stream: &mut SplitStream<DuplexStream>) -> Option<Message> {
+    while let Some(msg) = ws_stream.next().await {
+        match msg {
+            Ok(msg @ (Message::Text(_) | Message::Binary(_))) => return Some(msg),
+            Ok(Message::Close(_)) | Err(_) => return None,
+            Ok(_) => {},
+        }
+    }
+    None
+}
+
+/// Latest messages of a room, for the same clients that may connect.
+#[rocket::get("/history/<room>?<limit>")]
+pub async fn history(room: &str, limit: Option<usize>, _user: Authenticated, state: &State<Arc<ChatRooms>>) -> Json<Vec<ChatMessage>> {
+    let limit = limit.unwrap_or(HISTORY_DEFAULT_LIMIT).min(HISTORY_MAX_LIMIT);
+    let messages = state.history(room, limit).await
+        .into_iter()
+        .map(|event| event.message)
+        .collect();
+    Json(messages)
+}
+
+#[derive(Deserialize)]
+pub struct Credentials {
+    username: String,
+    password: String,
+}
+
+fn account_error(err: AccountError) -> (Status, String) {
+    if let AccountError::Internal(reason) = &err {
+        log::warn!("Account request failed: {}", reason);
+    }
+    (err.status(), err.to_string())
+}
+
+#[rocket::post("/accounts/register", data = "<credentials>")]
+pub async fn register(credentials: Json<Credentials>, addr: ClientAddr, accounts: &State<Accounts>) -> Result<Status, (Status, String)> {
+    let Credentials { username, password } = credentials.into_inner();
+    accounts.register(addr.0, username, password).await.map_err(account_error)?;
+    Ok(Status::Created)
+}
+
+#[rocket::post("/accounts/login", data = "<credentials>")]
+pub async fn login(credentials: Json<Credentials>, addr: ClientAddr, accounts: &State<Accounts>) -> Result<Json<Session>, (Status, String)> {
+    let Credentials { username, password } = credentials.into_inner();
+    let session = accounts.login(addr.0, username, password).await.map_err(account_error)?;
+    Ok(Json(session))
+}
+
+#[rocket::post("/accounts/logout")]
+pub async fn logout(token: BearerToken<'_>, accounts: &State<Accounts>) -> Result<Status, (Status, String)> {
+    accounts.logout(token.0).await.map_err(account_error)?;
+    Ok(Status::NoContent)
 }
@@ -0,0 +1,444 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use rocket::request::{FromRequest, Outcome, Request};
+
+use common::{ChatError, ClientMessage, ErrorCode};
+
+use crate::config::{ChatConfig, RateLimit};
+
+/// Violations older than this are forgiven.
+const STRIKE_WINDOW: Duration = Duration::from_secs(60);
+/// IP addresses tracked before idle ones are forgotten.
+const MAX_TRACKED_IPS: usize = 1024;
+/// Flooders tracked before those neither muted nor recently limited are
+/// forgotten.
+const MAX_TRACKED_FLOODERS: usize = 1024;
+
+
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit, now: Instant) -> TokenBucket {
+        TokenBucket {
+            limit,
+            tokens: limit.burst as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        let refilled = self.tokens + elapsed * self.limit.per_minute as f64 / 60.0;
+        self.tokens = refilled.min(self.limit.burst as f64);
+        self.updated = now;
+    }
+
+    fn has_token(&mut self, now: Instant) -> bool {
+        if self.limit.burst == 0 {
+            return true;
+        }
+        self.refill(now);
+        self.tokens >= 1.0
+    }
+
+    /// Takes a token that `has_token` found.
+    fn take(&mut self) {
+        if self.limit.burst != 0 {
+            self.tokens -= 1.0;
+        }
+    }
+
+    fn try_take(&mut self, now: Instant) -> bool {
+        let has_token = self.has_token(now);
+        if has_token {
+            self.take();
+        }
+        has_token
+    }
+
+    /// Whether the bucket is back to where a new one would start.
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.limit.burst as f64
+    }
+}
+
+/// Address a request came from: the TCP peer, or the address a trusted
+/// proxy put in Rocket's `ip_header`. The header of anyone else is
+/// ignored, so clients cannot pick a fresh address for every connection.
+pub struct ClientAddr(pub Option<IpAddr>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientAddr {
+    type Error = Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let peer = req.remote().map(|addr| addr.ip());
+        let forwarded = req.rocket().config().ip_header.as_ref()
+            .map(|header| req.headers().get(header.as_str()).collect::<Vec<_>>().join(","));
+        let trusted = req.rocket().state::<Arc<ChatConfig>>()
+            .map_or(&[][..], |config| &config.trusted_proxies[..]);
+        Outcome::Success(ClientAddr(client_ip(peer, forwarded.as_deref(), trusted)))
+    }
+}
+
+/// Walks back from the peer through the addresses in `forwarded`, which
+/// lists them like `X-Forwarded-For` with the nearest last, for as long as
+/// they are trusted proxies. Returns the first one that is not.
+fn client_ip(peer: Option<IpAddr>, forwarded: Option<&str>, trusted: &[IpAddr]) -> Option<IpAddr> {
+    let mut ip = peer?;
+    let hops = forwarded.unwrap_or_default().rsplit(',').filter(|hop| !hop.trim().is_empty());
+    for hop in hops {
+        if !trusted.contains(&ip) {
+            break;
+        }
+        match hop.trim().parse() {
+            Ok(hop) => ip = hop,
+            // Whatever came before a malformed entry cannot be trusted.
+            Err(_) => break,
+        }
+    }
+    Some(ip)
+}
+
+/// Token buckets shared by everything coming from one IP address.
+pub struct IpLimiter {
+    buckets: Mutex<HashMap<IpAddr, TokenBucket>>,
+    limit: RateLimit,
+}
+
+impl IpLimiter {
+    pub fn new(limit: RateLimit) -> IpLimiter {
+        IpLimiter {
+            buckets: Mutex::default(),
+            limit,
+        }
+    }
+
+    /// Takes a token from the address's bucket. Requests without an
+    /// address are not limited.
+    pub fn try_take(&self, ip: Option<IpAddr>, now: Instant) -> bool {
+        let ip = match ip {
+            Some(ip) => ip,
+            None => return true,
+        };
+        let mut buckets = self.buckets.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if buckets.len() >= MAX_TRACKED_IPS && !buckets.contains_key(&ip) {
+            // A full bucket is no different from a new one.
+            buckets.retain(|_, bucket| !bucket.is_full(now));
+        }
+        buckets.entry(ip)
+            .or_insert_with(|| TokenBucket::new(self.limit, now))
+            .try_take(now)
+    }
+}
+
+/// Requests that are rate limited, each with a bucket of its own.
+#[derive(Clone, Copy)]
+enum Limited {
+    Message,
+    UsernameChange,
+    UserList,
+    Presence,
+    Typing,
+    Reaction,
+    Read,
+    Room,
+    Thread,
+}
+
+impl Limited {
+    fn of(msg: &ClientMessage) -> Option<Limited> {
+        match msg {
+            ClientMessage::NewMessage { .. }
+            | ClientMessage::DirectMessage { .. }
+            | ClientMessage::EditMessage { .. }
+            | ClientMessage::DeleteMessage { .. } => Some(Limited::Message),
+            ClientMessage::UsernameChange { .. } => Some(Limited::UsernameChange),
+            ClientMessage::UserList { .. } | ClientMessage::RoomList => Some(Limited::UserList),
+            ClientMessage::Presence { .. } => Some(Limited::Presence),
+            ClientMessage::Typing { .. } => Some(Limited::Typing),
+            ClientMessage::AddReaction { .. } | ClientMessage::RemoveReaction { .. } => Some(Limited::Reaction),
+            ClientMessage::Read { .. } => Some(Limited::Read),
+            ClientMessage::JoinRoom { .. } | ClientMessage::LeaveRoom { .. } => Some(Limited::Room),
+            ClientMessage::Thread { .. } => Some(Limited::Thread),
+            _ => None,
+        }
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            Limited::Message => "sending messages",
+            Limited::UsernameChange => "changing your username",
+            Limited::UserList => "asking for user and room lists",
+            Limited::Presence => "changing your presence",
+            Limited::Typing => "sending typing notices",
+            Limited::Reaction => "reacting",
+            Limited::Read => "marking messages read",
+            Limited::Room => "joining and leaving rooms",
+            Limited::Thread => "opening threads",
+        }
+    }
+}
+
+/// Rate limits of one connection.
+pub struct ConnectionLimits {
+    ip: Option<IpAddr>,
+    messages: TokenBucket,
+    username_changes: TokenBucket,
+    user_lists: TokenBucket,
+    presence_changes: TokenBucket,
+    typing: TokenBucket,
+    reactions: TokenBucket,
+    reads: TokenBucket,
+    rooms: TokenBucket,
+    threads: TokenBucket,
+}
+
+impl ConnectionLimits {
+    fn bucket(&mut self, limited: Limited) -> &mut TokenBucket {
+        match limited {
+            Limited::Message => &mut self.messages,
+            Limited::UsernameChange => &mut self.username_changes,
+            Limited::UserList => &mut self.user_lists,
+            Limited::Presence => &mut self.presence_changes,
+            Limited::Typing => &mut self.typing,
+            Limited::Reaction => &mut self.reactions,
+            Limited::Read => &mut self.reads,
+            Limited::Room => &mut self.rooms,
+            Limited::Thread => &mut self.threads,
+        }
+    }
+}
+
+/// Recent violations of one flooder, and whether they are muted.
+#[derive(Default)]
+struct Strikes {
+    strikes: Vec<Instant>,
+    muted_until: Option<Instant>,
+}
+
+impl Strikes {
+    /// Whether there is nothing left to remember.
+    fn is_idle(&self, now: Instant) -> bool {
+        self.muted_until.is_none_or(|until| until <= now)
+            && self.strikes.iter().all(|strike| now.saturating_duration_since(*strike) >= STRIKE_WINDOW)
+    }
+}
+
+/// Checks requests against the per-connection and per-IP limits and
+/// mutes flooders that keep hitting them. Mutes are kept by flooder
+/// rather than by connection, so reconnecting does not lift them.
+pub struct FloodGuard {
+    ips: IpLimiter,
+    flooders: Mutex<HashMap<String, Strikes>>,
+    config: Arc<ChatConfig>,
+}
+
+impl FloodGuard {
+    pub fn new(config: Arc<ChatConfig>) -> FloodGuard {
+        FloodGuard {
+            ips: IpLimiter::new(config.ip_limit),
+            flooders: Mutex::default(),
+            config,
+        }
+    }
+
+    pub fn connection_limits(&self, ip: Option<IpAddr>) -> ConnectionLimits {
+        let now = Instant::now();
+        ConnectionLimits {
+            ip,
+            messages: TokenBucket::new(self.config.message_limit, now),
+            username_changes: TokenBucket::new(self.config.username_limit, now),
+            user_lists: TokenBucket::new(self.config.user_list_limit, now),
+            presence_changes: TokenBucket::new(self.config.presence_limit, now),
+            typing: TokenBucket::new(self.config.typing_limit, now),
+            reactions: TokenBucket::new(self.config.reaction_limit, now),
+            reads: TokenBucket::new(self.config.read_limit, now),
+            rooms: TokenBucket::new(self.config.room_limit, now),
+            threads: TokenBucket::new(self.config.thread_limit, now),
+        }
+    }
+
+    /// Takes a token for the request if it is limited. Fails while the
+    /// flooder is muted or when the connection's own or its IP's bucket
+    /// is empty. `flooder` is who strikes and mutes are kept for, see
+    /// `ChatSession::flooder`.
+    pub fn check(&self, limits: &mut ConnectionLimits, flooder: &str, msg: &ClientMessage) -> Result<(), ChatError> {
+        let limited = match Limited::of(msg) {
+            Some(limited) => limited,
+            None => return Ok(()),
+        };
+        let now = Instant::now();
+        let mut flooders = self.flooders.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(muted_until) = flooders.get(flooder).and_then(|strikes| strikes.muted_until).filter(|until| *until > now) {
+            return Err(muted(muted_until - now));
+        }
+
+        // Neither bucket is charged unless both have a token.
+        let ip = limits.ip;
+        let bucket = limits.bucket(limited);
+        if bucket.has_token(now) && self.ips.try_take(ip, now) {
+            bucket.take();
+            return Ok(());
+        }
+
+        if flooders.len() >= MAX_TRACKED_FLOODERS && !flooders.contains_key(flooder) {
+            flooders.retain(|_, strikes| !strikes.is_idle(now));
+        }
+        let strikes = flooders.entry(flooder.to_string()).or_default();
+        strikes.strikes.retain(|strike| now.saturating_duration_since(*strike) < STRIKE_WINDOW);
+        strikes.strikes.push(now);
+        if self.config.mute_after > 0 && strikes.strikes.len() >= self.config.mute_after as usize {
+            let mute = Duration::from_secs(self.config.mute_seconds);
+            strikes.strikes.clear();
+            strikes.muted_until = Some(now + mute);
+            return Err(muted(mute));
+        }
+        Err(ChatError::new(
+            ErrorCode::RateLimited,
+            format!("Slow down, you are {} too fast", limited.describe()),
+        ))
+    }
+
+}
+
+fn muted(remaining: Duration) -> ChatError {
+    ChatError::new(
+        ErrorCode::RateLimited,
+        format!("You are muted for {} more seconds for flooding", remaining.as_secs_f64().ceil()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::local::blocking::Client;
+
+    use super::*;
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    fn message() -> ClientMessage {
+        ClientMessage::NewMessage { room: None, text: "hi".to_string(), reply_to: None }
+    }
+
+    #[test]
+    fn buckets_refill_up_to_their_burst() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(RateLimit { burst: 2, per_minute: 60 }, start);
+        assert!(bucket.try_take(start));
+        assert!(bucket.try_take(start));
+        assert!(!bucket.try_take(start));
+        assert!(!bucket.try_take(start + Duration::from_millis(500)));
+        assert!(bucket.try_take(start + Duration::from_secs(1)));
+
+        let later = start + Duration::from_secs(60);
+        assert!(bucket.is_full(later));
+        assert!(bucket.try_take(later));
+        assert!(bucket.try_take(later));
+        assert!(!bucket.try_take(later));
+    }
+
+    #[test]
+    fn zero_burst_disables_a_limit() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(RateLimit { burst: 0, per_minute: 0 }, now);
+        assert!((0..100).all(|_| bucket.try_take(now)));
+    }
+
+    #[test]
+    fn repeat_offenders_are_muted_across_connections() {
+        let guard = FloodGuard::new(Arc::new(ChatConfig {
+            message_limit: RateLimit { burst: 1, per_minute: 1 },
+            mute_after: 3,
+            mute_seconds: 60,
+            ..ChatConfig::default()
+        }));
+        let mut limits = guard.connection_limits(None);
+        assert!(guard.check(&mut limits, "alice", &message()).is_ok());
+        for _ in 0..2 {
+            let err = guard.check(&mut limits, "alice", &message()).unwrap_err();
+            assert!(err.text.starts_with("Slow down"), "{}", err.text);
+        }
+        let err = guard.check(&mut limits, "alice", &message()).unwrap_err();
+        assert!(err.text.contains("muted"), "{}", err.text);
+
+        // A new connection starts with a full bucket but stays muted.
+        let mut reconnected = guard.connection_limits(None);
+        assert!(guard.check(&mut reconnected, "alice", &message()).unwrap_err().text.contains("muted"));
+        assert!(guard.check(&mut reconnected, "bob", &message()).is_ok());
+        // Requests that are not limited are let through.
+        assert!(guard.check(&mut reconnected, "alice", &ClientMessage::Hello { version: 3, capabilities: Vec::new() }).is_ok());
+    }
+
+    #[test]
+    fn ip_limit_does_not_charge_the_connection() {
+        let guard = FloodGuard::new(Arc::new(ChatConfig {
+            message_limit: RateLimit { burst: 2, per_minute: 1 },
+            ip_limit: RateLimit { burst: 1, per_minute: 1 },
+            mute_after: 0,
+            ..ChatConfig::default()
+        }));
+        let address = Some(ip("203.0.113.7"));
+        let mut first = guard.connection_limits(address);
+        let mut second = guard.connection_limits(address);
+        assert!(guard.check(&mut first, "first", &message()).is_ok());
+        assert!(guard.check(&mut second, "second", &message()).is_err());
+        assert!(second.messages.tokens >= 2.0);
+        // Other addresses have buckets of their own.
+        let mut elsewhere = guard.connection_limits(Some(ip("198.51.100.1")));
+        assert!(guard.check(&mut elsewhere, "third", &message()).is_ok());
+    }
+
+    #[test]
+    fn forwarded_addresses_count_only_from_trusted_proxies() {
+        let proxy = ip("10.0.0.1");
+        let inner_proxy = ip("10.0.0.2");
+        let trusted = [proxy, inner_proxy];
+        let client = ip("203.0.113.7");
+
+        assert_eq!(client_ip(Some(client), Some("198.51.100.1"), &trusted), Some(client));
+        assert_eq!(client_ip(Some(proxy), None, &trusted), Some(proxy));
+        assert_eq!(client_ip(Some(proxy), Some("203.0.113.7"), &trusted), Some(client));
+        // Entries a client put before the proxies' own are skipped.
+        assert_eq!(client_ip(Some(proxy), Some("1.1.1.1, 203.0.113.7, 10.0.0.2"), &trusted), Some(client));
+        assert_eq!(client_ip(Some(proxy), Some("garbage, 10.0.0.2"), &trusted), Some(inner_proxy));
+        assert_eq!(client_ip(None, Some("203.0.113.7"), &trusted), None);
+    }
+
+    #[rocket::get("/addr")]
+    fn addr(addr: ClientAddr) -> String {
+        addr.0.map(|ip| ip.to_string()).unwrap_or_default()
+    }
+
+    #[test]
+    fn client_addr_reads_the_configured_header() {
+        let config = ChatConfig {
+            trusted_proxies: vec![ip("10.0.0.1")],
+            ..ChatConfig::default()
+        };
+        let figment = rocket::Config::figment().merge(("ip_header", "X-Forwarded-For"));
+        let rocket = rocket::custom(figment)
+            .mount("/", rocket::routes![addr])
+            .manage(Arc::new(config));
+        let client = Client::untracked(rocket).unwrap();
+        let get = |peer: &str| client.get("/addr")
+            .remote(format!("{}:4000", peer).parse().unwrap())
+            .header(rocket::http::Header::new("X-Forwarded-For", "1.1.1.1, 203.0.113.7"));
+
+        assert_eq!(get("10.0.0.1").dispatch().into_string().unwrap(), "203.0.113.7");
+        assert_eq!(get("198.51.100.1").dispatch().into_string().unwrap(), "198.51.100.1");
+    }
+}
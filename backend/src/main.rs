@@ -1,9 +1,21 @@
-use rocket;
-use env_logger;
+use std::{io, process, sync::Arc};
 
+
+mod accounts;
+mod auth;
+mod bus;
 mod chat;
+mod config;
+mod fanout;
 mod handlers;
+mod limits;
 mod metrics;
+mod moderation;
+mod outbox;
+mod protocol;
+mod rooms;
+mod storage;
+mod usernames;
 
 
 #[rocket::main]
@@ -12,13 +24,69 @@ async fn main() {
     let prom = metrics::get_prometheus();
 
     log::info!("Starting ws server...");
-    let _ = rocket::build()
+    let rocket = rocket::build();
+    let config: config::ChatConfig = rocket.figment().extract()
+        .expect("Cannot read chat config");
+    let config = Arc::new(config);
+    let storage = storage::from_config(&config);
+    let auth = auth::from_config(&config, storage.accounts.clone());
+    let accounts = accounts::Accounts::new(storage.accounts.clone(), config.clone());
+
+    // `backend add-account <username>` creates an account, e.g. for a name
+    // listed in `admins`, with the password read from stdin, and exits.
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.as_slice() {
+        [] => {},
+        [command, username] if command == "add-account" => {
+            let mut password = String::new();
+            io::stdin().read_line(&mut password).expect("Cannot read password");
+            let password = password.trim_end_matches(['\r', '\n']).to_string();
+            match accounts.add_account(username.clone(), password).await {
+                Ok(()) => return,
+                Err(err) => {
+                    eprintln!("Cannot add account {}: {}", username, err);
+                    process::exit(1);
+                }
+            }
+        },
+        _ => {
+            eprintln!("Usage: backend [add-account <username>]");
+            process::exit(2);
+        },
+    }
+
+    let bus = bus::from_config(&config);
+    let (fanout, events) = fanout::FanOut::new(&config);
+    let rooms = Arc::new(rooms::ChatRooms::new(
+        storage.messages,
+        storage.accounts,
+        storage.bans,
+        Arc::new(fanout),
+        config.clone(),
+    ));
+    fanout::start(rooms.clone(), bus, events);
+
+    let mut rocket = rocket
         .attach(prom.clone())
         .mount("/", rocket::routes![
             handlers::chat,
+            handlers::chat_room,
+            handlers::history,
         ])
-        .mount("/metrics", prom)
-        .manage(chat::ChatRoom::default())
+        .mount("/metrics", prom);
+    // Sessions are never accepted with tokens or jwts, so neither are logins.
+    if config.auth.uses_accounts() {
+        rocket = rocket.mount("/", rocket::routes![
+            handlers::register,
+            handlers::login,
+            handlers::logout,
+        ]);
+    }
+    let _ = rocket
+        .manage(config)
+        .manage(auth)
+        .manage(accounts)
+        .manage(rooms)
         .launch()
         .await;
 
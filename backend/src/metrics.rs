@@ -1,6 +1,5 @@
 use rocket_prometheus::{self, prometheus::{IntCounter, IntGauge}, PrometheusMetrics};
 use once_cell::sync::Lazy;
-use log;
 
 
 pub static WS_CONNECTIONS_TOTAL: Lazy<IntGauge> = Lazy::new(|| {
@@ -13,6 +12,16 @@ pub static WS_NEW_CONNECTIONS_TOTAL: Lazy<IntCounter> = Lazy::new(|| {
         .expect("Cannot create new_connections_counter metric")
 });
 
+pub static WS_DROPPED_MESSAGES_TOTAL: Lazy<IntCounter> = Lazy::new(|| {
+    IntCounter::new("ws_server_dropped_messages_total", "a counter of messages dropped from full outbound queues")
+        .expect("Cannot create dropped_messages_counter metric")
+});
+
+pub static WS_EVICTED_CONNECTIONS_TOTAL: Lazy<IntCounter> = Lazy::new(|| {
+    IntCounter::new("ws_server_evicted_connections_total", "a counter of connections dropped for not keeping up")
+        .expect("Cannot create evicted_connections_counter metric")
+});
+
 pub fn get_prometheus() -> PrometheusMetrics {
     log::info!("Setting up prometheus metrics");
     let prom = PrometheusMetrics::new();
@@ -20,5 +29,9 @@ pub fn get_prometheus() -> PrometheusMetrics {
         .expect("Cannot register ws connection amount metric");
     prom.registry().register(Box::new(WS_NEW_CONNECTIONS_TOTAL.clone()))
         .expect("Cannot register new_connections_counter metric");
+    prom.registry().register(Box::new(WS_DROPPED_MESSAGES_TOTAL.clone()))
+        .expect("Cannot register dropped_messages_counter metric");
+    prom.registry().register(Box::new(WS_EVICTED_CONNECTIONS_TOTAL.clone()))
+        .expect("Cannot register evicted_connections_counter metric");
     prom
 }
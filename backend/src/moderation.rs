@@ -0,0 +1,299 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use chrono::{NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use common::{ChatError, ClientMessage, ErrorCode};
+
+use crate::config::ChatConfig;
+use crate::storage::{self, Ban, BanStore};
+use crate::usernames;
+
+
+/// What a connection may do, from least to most.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+pub enum Role {
+    #[default]
+    User,
+    /// May kick, ban and mute users.
+    Moderator,
+    /// May also kick, ban and mute moderators.
+    Admin,
+}
+
+/// What a moderator did to a user. Every instance applies it to the
+/// connections of the user it holds.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Sanction {
+    Kick,
+    /// With `ip` the addresses the user is connected from are banned too.
+    Ban { ip: bool, expires_at: Option<NaiveDateTime> },
+    /// A mute of `0` seconds lifts it.
+    Mute { seconds: u64 },
+}
+
+/// Who a mute is held against.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+enum Muted {
+    /// A username skeleton, so look-alikes are muted too.
+    Name(String),
+    /// The address of a guest, whose name changes when they reconnect.
+    Address(IpAddr),
+}
+
+/// Roles, bans and mutes. Roles come from the config and only count for
+/// authenticated connections; bans are kept in the store, which is called
+/// on the blocking pool, mutes last as long as the instance.
+pub struct Moderation {
+    bans: Arc<dyn BanStore>,
+    /// When mutes end.
+    mutes: Mutex<HashMap<Muted, Instant>>,
+    config: Arc<ChatConfig>,
+}
+
+impl Moderation {
+    pub fn new(bans: Arc<dyn BanStore>, config: Arc<ChatConfig>) -> Moderation {
+        Moderation {
+            bans,
+            mutes: Mutex::default(),
+            config,
+        }
+    }
+
+    fn mutes(&self) -> MutexGuard<'_, HashMap<Muted, Instant>> {
+        self.mutes.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Role the config gives to a username. Neither anonymous users nor
+    /// `/accounts/register` can take these names, so it is also the role of
+    /// whoever is authenticated under the name.
+    pub fn role_of(&self, username: &str) -> Role {
+        if self.config.admins.iter().any(|admin| admin == username) {
+            Role::Admin
+        } else if self.config.moderators.iter().any(|moderator| moderator == username) {
+            Role::Moderator
+        } else {
+            Role::User
+        }
+    }
+
+    pub fn is_staff_name(&self, username: &str) -> bool {
+        is_staff_name(&self.config, username)
+    }
+
+    /// Fails unless a connection with `role` may moderate `target`, who
+    /// has to have a lower role.
+    pub fn authorize(&self, role: Role, target: &str) -> Result<(), ChatError> {
+        require_moderator(role)?;
+        if self.role_of(target) >= role {
+            return Err(ChatError::new(ErrorCode::Forbidden, format!("You cannot moderate {}", target)));
+        }
+        Ok(())
+    }
+
+    /// Fails with a notice for the user if the username, one that looks
+    /// like it or the address is banned.
+    pub async fn check_ban(&self, username: Option<&str>, ip: Option<IpAddr>) -> Result<(), ChatError> {
+        let skeleton = username.map(usernames::skeleton_key);
+        let ip = ip.map(|ip| ip.to_string());
+        let now = Utc::now().naive_utc();
+        let bans = self.bans.clone();
+        let ban = storage::run_blocking(move || bans.active_ban(skeleton.as_deref(), ip.as_deref(), now)).await;
+        match ban {
+            Ok(None) => Ok(()),
+            Ok(Some(ban)) => Err(banned(&ban)),
+            Err(err) => {
+                log::warn!("Cannot look up bans: {}", err);
+                Err(ChatError::new(ErrorCode::Internal, "Cannot check bans, try again later"))
+            }
+        }
+    }
+
+    pub async fn ban(&self, ban: &Ban) -> Result<(), ChatError> {
+        let (bans, stored) = (self.bans.clone(), ban.clone());
+        storage::run_blocking(move || bans.create_ban(&stored)).await.map_err(|err| {
+            log::warn!("Cannot store ban of {}: {}", ban.username, err);
+            ChatError::new(ErrorCode::Internal, "Cannot store the ban, try again later")
+        })
+    }
+
+    /// Lifts the bans of a user and of their addresses. Returns how many
+    /// there were.
+    pub async fn unban(&self, username: &str) -> Result<usize, ChatError> {
+        let skeleton = usernames::normalize(username)
+            .map(|username| usernames::skeleton_key(&username))
+            .map_err(|err| ChatError::new(ErrorCode::InvalidUsername, format!("Cannot unban {:?}: {}", username, err)))?;
+        let bans = self.bans.clone();
+        storage::run_blocking(move || bans.delete_bans(&skeleton)).await.map_err(|err| {
+            log::warn!("Cannot lift bans of {}: {}", username, err);
+            ChatError::new(ErrorCode::Internal, "Cannot lift the bans, try again later")
+        })
+    }
+
+    /// Lifts the bans of an address. Returns how many there were.
+    pub async fn unban_ip(&self, ip: IpAddr) -> Result<usize, ChatError> {
+        let bans = self.bans.clone();
+        storage::run_blocking(move || bans.delete_ip_bans(&ip.to_string())).await.map_err(|err| {
+            log::warn!("Cannot lift bans of {}: {}", ip, err);
+            ChatError::new(ErrorCode::Internal, "Cannot lift the bans, try again later")
+        })
+    }
+
+    /// Mutes a username and the addresses it is connected from as a guest,
+    /// or lifts their mutes with `0` seconds.
+    pub fn mute(&self, username: &str, guest_addresses: &[IpAddr], seconds: u64) {
+        let now = Instant::now();
+        let mut mutes = self.mutes();
+        mutes.retain(|_, until| *until > now);
+        let muted = guest_addresses.iter()
+            .map(|address| Muted::Address(*address))
+            .chain([Muted::Name(usernames::skeleton_key(username))]);
+        for muted in muted {
+            if seconds == 0 {
+                mutes.remove(&muted);
+            } else {
+                mutes.insert(muted, now + Duration::from_secs(seconds));
+            }
+        }
+    }
+
+    /// Fails while the user, or the guest address, is muted and the request
+    /// would post something, status texts and new room names included, or
+    /// shake off the mute with a new username.
+    pub fn check(&self, username: &str, guest_address: Option<IpAddr>, msg: &ClientMessage) -> Result<(), ChatError> {
+        match msg {
+            ClientMessage::NewMessage { .. }
+            | ClientMessage::DirectMessage { .. }
+            | ClientMessage::EditMessage { .. }
+            | ClientMessage::AddReaction { .. }
+            | ClientMessage::Presence { text: Some(_), .. }
+            | ClientMessage::JoinRoom { .. }
+            | ClientMessage::UsernameChange { .. } => {},
+            _ => return Ok(()),
+        }
+        let now = Instant::now();
+        let until = {
+            let mutes = self.mutes();
+            guest_address.map(Muted::Address).into_iter()
+                .chain([Muted::Name(usernames::skeleton_key(username))])
+                .filter_map(|muted| mutes.get(&muted).copied())
+                .max()
+        };
+        match until.filter(|until| *until > now) {
+            Some(until) => {
+                // Rounded up to whole minutes past the first one.
+                let secs = (until - now).as_secs() + 1;
+                let secs = if secs > 60 { secs.div_ceil(60) * 60 } else { secs };
+                Err(ChatError::new(
+                    ErrorCode::Forbidden,
+                    format!("You are muted by a moderator for {}", describe_duration(secs)),
+                ))
+            },
+            None => Ok(()),
+        }
+    }
+}
+
+/// Fails unless the connection is a moderator or an admin.
+pub fn require_moderator(role: Role) -> Result<(), ChatError> {
+    if role < Role::Moderator {
+        return Err(ChatError::new(ErrorCode::Forbidden, "Only moderators can do this"));
+    }
+    Ok(())
+}
+
+/// Whether the name looks like the name of a moderator or an admin.
+pub fn is_staff_name(config: &ChatConfig, username: &str) -> bool {
+    config.admins.iter()
+        .chain(config.moderators.iter())
+        .any(|staff| usernames::is_confusable(staff, username))
+}
+
+/// Human readable length of a ban or mute, e.g. "2 hours".
+pub fn describe_duration(seconds: u64) -> String {
+    let (count, unit) = match seconds {
+        s if s >= 86_400 && s % 86_400 == 0 => (s / 86_400, "day"),
+        s if s >= 3_600 && s % 3_600 == 0 => (s / 3_600, "hour"),
+        s if s >= 60 && s % 60 == 0 => (s / 60, "minute"),
+        s => (s, "second"),
+    };
+    if count == 1 {
+        format!("1 {}", unit)
+    } else {
+        format!("{} {}s", count, unit)
+    }
+}
+
+fn banned(ban: &Ban) -> ChatError {
+    let until = match ban.expires_at {
+        Some(expires_at) => format!("until {} UTC", expires_at.format("%Y-%m-%d %H:%M")),
+        None => "for good".to_string(),
+    };
+    let reason = match &ban.reason {
+        Some(reason) => format!(": {}", reason),
+        None => String::new(),
+    };
+    ChatError::new(ErrorCode::Forbidden, format!("You are banned {}{}", until, reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::{MemoryStore, SqliteStore};
+
+    use super::*;
+
+    fn address_ban(ip: &str) -> Ban {
+        Ban {
+            username: ip.to_string(),
+            skeleton: None,
+            ip: Some(ip.to_string()),
+            reason: None,
+            banned_by: "mod".to_string(),
+            created_at: Utc::now().naive_utc(),
+            expires_at: None,
+        }
+    }
+
+    async fn unban_keeps_address_bans(bans: Arc<dyn BanStore>) {
+        let moderation = Moderation::new(bans.clone(), Arc::new(ChatConfig::default()));
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        moderation.ban(&address_ban(&ip.to_string())).await.unwrap();
+
+        assert_eq!(moderation.unban("").await.unwrap_err().code, ErrorCode::InvalidUsername);
+        assert_eq!(bans.delete_bans("").unwrap(), 0);
+        assert_eq!(moderation.unban("alice").await.unwrap(), 0);
+        assert!(moderation.check_ban(None, Some(ip)).await.is_err());
+        assert_eq!(moderation.unban_ip(ip).await.unwrap(), 1);
+        assert!(moderation.check_ban(None, Some(ip)).await.is_ok());
+    }
+
+    #[test]
+    fn guest_mutes_outlast_their_name() {
+        let moderation = Moderation::new(Arc::new(MemoryStore::new(10)), Arc::new(ChatConfig::default()));
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        let post = ClientMessage::NewMessage { room: None, text: "hi".to_string(), reply_to: None };
+        moderation.mute("user #1", &[ip], 60);
+
+        assert!(moderation.check("user #2", Some(ip), &post).is_err());
+        assert!(moderation.check("user #2", None, &post).is_ok());
+        assert!(moderation.check("user #2", Some(ip), &ClientMessage::RoomList).is_ok());
+        moderation.mute("user #1", &[ip], 0);
+        assert!(moderation.check("user #2", Some(ip), &post).is_ok());
+    }
+
+    #[rocket::async_test]
+    async fn unban_keeps_address_bans_in_memory() {
+        unban_keeps_address_bans(Arc::new(MemoryStore::new(10))).await;
+    }
+
+    #[rocket::async_test]
+    async fn unban_keeps_address_bans_in_sqlite() {
+        unban_keeps_address_bans(Arc::new(SqliteStore::open(":memory:").unwrap())).await;
+    }
+}
@@ -0,0 +1,205 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use rocket::{
+    futures::{stream::SplitSink, SinkExt},
+    tokio::{self, sync::Notify, task::JoinHandle, time::timeout},
+};
+use rocket_ws::{frame::{CloseCode, CloseFrame}, stream::DuplexStream, Message};
+
+use common::ServerMessage;
+
+use crate::config::{ChatConfig, SlowConsumerPolicy};
+use crate::metrics::{WS_DROPPED_MESSAGES_TOTAL, WS_EVICTED_CONNECTIONS_TOTAL};
+use crate::protocol::ClientProtocol;
+
+
+/// Write half of a websocket, owned by its writer task.
+pub type WsSink = SplitSink<DuplexStream, Message>;
+
+const EVICTION_NOTICE: &str = "You were disconnected because messages to you were piling up";
+
+#[derive(Default)]
+struct Queue {
+    frames: VecDeque<Message>,
+    closed: bool,
+}
+
+/// Bounded queue of frames waiting to be written to one websocket. Rooms
+/// only push to it; a writer task per connection does the slow socket
+/// writes, so one slow client does not hold up the others.
+#[derive(Clone)]
+pub struct Outbox {
+    user_id: usize,
+    capacity: usize,
+    policy: SlowConsumerPolicy,
+    send_timeout: Duration,
+    queue: Arc<Mutex<Queue>>,
+    notify: Arc<Notify>,
+}
+
+impl Outbox {
+    /// Creates the queue and spawns the task writing it to `sink`. The
+    /// task ends once the outbox is closed and drained, or the socket
+    /// fails or stalls.
+    pub fn spawn(user_id: usize, sink: WsSink, config: &ChatConfig) -> (Outbox, JoinHandle<()>) {
+        let outbox = Outbox::detached(user_id, config);
+        let writer = tokio::spawn(outbox.clone().write(sink));
+        (outbox, writer)
+    }
+
+    /// Queue without a writer task; frames stay queued.
+    pub fn detached(user_id: usize, config: &ChatConfig) -> Outbox {
+        Outbox {
+            user_id,
+            capacity: config.outbound_queue_size,
+            policy: config.slow_consumer,
+            send_timeout: Duration::from_millis(config.send_timeout_ms),
+            queue: Arc::default(),
+            notify: Arc::default(),
+        }
+    }
+
+    /// Frames queued and not written yet.
+    #[cfg(test)]
+    pub fn queued(&self) -> Vec<Message> {
+        self.lock().frames.iter().cloned().collect()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        // The queue holds no invariant a panicking pusher could break.
+        self.queue.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Queues the message in the client's protocol without waiting.
+    /// Returns whether it was queued; messages the client does not
+    /// understand are skipped. A full queue is handled by the slow
+    /// consumer policy.
+    pub fn send(&self, protocol: &ClientProtocol, msg: &ServerMessage) -> bool {
+        let frame = match protocol.encode(msg) {
+            Some(frame) => frame,
+            None => return false,
+        };
+        let queued = {
+            let mut queue = self.lock();
+            if queue.closed {
+                return false;
+            }
+            if queue.frames.len() < self.capacity {
+                queue.frames.push_back(frame);
+                true
+            } else {
+                match self.policy {
+                    SlowConsumerPolicy::DropOldest => {
+                        log::warn!("Outbound queue of user {} is full, dropping the oldest message", self.user_id);
+                        WS_DROPPED_MESSAGES_TOTAL.inc();
+                        queue.frames.pop_front();
+                        queue.frames.push_back(frame);
+                        true
+                    },
+                    SlowConsumerPolicy::Disconnect => {
+                        log::warn!("Outbound queue of user {} is full, disconnecting", self.user_id);
+                        WS_EVICTED_CONNECTIONS_TOTAL.inc();
+                        // Nothing queued matters any more, only why it ends.
+                        let notice = ServerMessage::system(None, EVICTION_NOTICE.to_string());
+                        queue.frames.clear();
+                        queue.frames.extend(protocol.encode(&notice));
+                        queue.frames.push_back(close_frame(CloseCode::Again, EVICTION_NOTICE));
+                        queue.closed = true;
+                        false
+                    },
+                }
+            }
+        };
+        self.notify.notify_one();
+        queued
+    }
+
+    /// Queues a close frame, past the size limit, and closes the outbox.
+    pub fn close_with(&self, code: CloseCode, reason: &str) {
+        {
+            let mut queue = self.lock();
+            if !queue.closed {
+                queue.frames.push_back(close_frame(code, reason));
+                queue.closed = true;
+            }
+        }
+        self.notify.notify_one();
+    }
+
+    /// Like `close_with`, but drops what is still queued.
+    pub fn close_now(&self, code: CloseCode, reason: &str) {
+        {
+            let mut queue = self.lock();
+            queue.frames.clear();
+            queue.frames.push_back(close_frame(code, reason));
+            queue.closed = true;
+        }
+        self.notify.notify_one();
+    }
+
+    /// Queues a ping, past the size limit.
+    pub fn ping(&self) {
+        {
+            let mut queue = self.lock();
+            if queue.closed {
+                return;
+            }
+            queue.frames.push_back(Message::Ping(Vec::new()));
+        }
+        self.notify.notify_one();
+    }
+
+    /// Stops accepting frames. What is already queued is still written.
+    pub fn close(&self) {
+        self.lock().closed = true;
+        self.notify.notify_one();
+    }
+
+    async fn next(&self) -> Option<Message> {
+        loop {
+            {
+                let mut queue = self.lock();
+                if let Some(frame) = queue.frames.pop_front() {
+                    return Some(frame);
+                }
+                if queue.closed {
+                    return None;
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    async fn write(self, mut sink: WsSink) {
+        while let Some(frame) = self.next().await {
+            match timeout(self.send_timeout, sink.send(frame)).await {
+                Ok(Ok(())) => {},
+                Ok(Err(err)) => {
+                    log::warn!("Cannot send a message to user {}: {}", self.user_id, err);
+                    break;
+                },
+                Err(_) => {
+                    log::warn!("Socket of user {} stalled, disconnecting", self.user_id);
+                    WS_EVICTED_CONNECTIONS_TOTAL.inc();
+                    break;
+                },
+            }
+        }
+        self.close();
+        // A stalled socket would not take the close either.
+        if let Ok(Err(err)) = timeout(self.send_timeout, sink.close()).await {
+            log::debug!("Cannot close connection of user {}: {}", self.user_id, err);
+        }
+    }
+}
+
+fn close_frame(code: CloseCode, reason: &str) -> Message {
+    Message::Close(Some(CloseFrame {
+        code,
+        reason: reason.to_string().into(),
+    }))
+}
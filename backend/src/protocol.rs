@@ -0,0 +1,215 @@
+use std::{collections::{BTreeMap, BTreeSet}, convert::Infallible, fmt::Display};
+
+use rocket::{
+    request::{FromRequest, Outcome, Request as HttpRequest},
+    response::{self, Responder},
+};
+use rocket_ws::{Channel, Message};
+use serde::Serialize;
+
+use common::{
+    capabilities, legacy::WebSocketMessage, ChatError, ErrorCode, Request, ServerMessage, WireFormat,
+    WebSocketMessageType, CAPABILITIES, PROTOCOL_VERSION, TAGGED_PROTOCOL_VERSION,
+};
+
+/// Version of clients that connect without a `Hello`. They get no
+/// optional capabilities.
+pub const LEGACY_PROTOCOL_VERSION: u32 = 1;
+
+/// The only kinds version 1 clients know; they fail on any other.
+const LEGACY_KINDS: &[WebSocketMessageType] = &[
+    WebSocketMessageType::NewMessage,
+    WebSocketMessageType::UserList,
+    WebSocketMessageType::UsernameChange,
+    WebSocketMessageType::System,
+];
+
+
+/// What a client understands, agreed on when it connected.
+pub struct ClientProtocol {
+    pub version: u32,
+    pub format: WireFormat,
+    capabilities: BTreeSet<String>,
+}
+
+impl ClientProtocol {
+    pub fn legacy(format: WireFormat) -> ClientProtocol {
+        ClientProtocol {
+            version: LEGACY_PROTOCOL_VERSION,
+            format,
+            capabilities: BTreeSet::new(),
+        }
+    }
+
+    /// Agrees on the lower of both versions and on the capabilities both
+    /// sides know. Versions below `min_version` are rejected.
+    pub fn negotiate(
+        version: u32,
+        capabilities: &[String],
+        min_version: u32,
+        format: WireFormat,
+    ) -> Result<ClientProtocol, ChatError> {
+        if version < min_version {
+            return Err(unsupported_version(version, min_version));
+        }
+        let capabilities = capabilities.iter()
+            .filter(|capability| CAPABILITIES.contains(&capability.as_str()))
+            .cloned()
+            .collect();
+        Ok(ClientProtocol {
+            version: version.min(PROTOCOL_VERSION),
+            format,
+            capabilities,
+        })
+    }
+
+    pub fn capabilities(&self) -> Vec<String> {
+        self.capabilities.iter().cloned().collect()
+    }
+
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.contains(capability)
+    }
+
+    /// Frame of the message for this client, downgraded to what it
+    /// understands. `None` if the client cannot receive it at all.
+    pub fn encode(&self, msg: &ServerMessage) -> Option<Message> {
+        let downgraded;
+        let msg = match (msg.kind().capability(), msg) {
+            (Some(capability), _) if !self.supports(capability) => match msg {
+                // Errors still reach old clients, as system messages.
+                ServerMessage::Error { error, .. } => {
+                    downgraded = ServerMessage::system(None, error.text.clone());
+                    &downgraded
+                },
+                _ => return None,
+            },
+            // So do user lists, without the presence of the users.
+            (_, ServerMessage::UserList { room, users, presence })
+                if !presence.is_empty() && !self.supports(capabilities::PRESENCE) => {
+                downgraded = ServerMessage::UserList {
+                    room: room.clone(),
+                    users: users.clone(),
+                    presence: BTreeMap::new(),
+                };
+                &downgraded
+            },
+            _ => msg,
+        };
+        if self.version <= LEGACY_PROTOCOL_VERSION && !LEGACY_KINDS.contains(&msg.kind()) {
+            return None;
+        }
+        if self.version >= TAGGED_PROTOCOL_VERSION {
+            self.frame(msg)
+        } else {
+            self.frame(&WebSocketMessage::from(msg))
+        }
+    }
+
+    fn frame<T: Serialize + Display>(&self, msg: &T) -> Option<Message> {
+        match self.format {
+            WireFormat::Json => Some(Message::Text(msg.to_string())),
+            WireFormat::MessagePack => match rmp_serde::to_vec_named(msg) {
+                Ok(bytes) => Some(Message::Binary(bytes)),
+                Err(err) => {
+                    log::warn!("Cannot encode message as MessagePack: {}", err);
+                    None
+                }
+            },
+        }
+    }
+}
+
+pub fn unsupported_version(version: u32, min_version: u32) -> ChatError {
+    ChatError::new(
+        ErrorCode::UnsupportedVersion,
+        format!(
+            "Protocol version {} is not supported, use {} to {}",
+            version, min_version, PROTOCOL_VERSION,
+        ),
+    )
+}
+
+/// Decodes a request from a text (JSON) or binary (MessagePack) frame,
+/// whatever format the client asked for. `None` for control frames.
+pub fn decode_request(frame: &Message) -> Option<Result<Request, (ChatError, Option<String>)>> {
+    match frame {
+        Message::Text(text) => Some(Request::decode(text)),
+        Message::Binary(bytes) => Some(Request::decode_msgpack(bytes)),
+        _ => None,
+    }
+}
+
+/// First supported format offered in the `Sec-WebSocket-Protocol` header.
+/// `None` if the client offered none; it then speaks JSON.
+pub struct Subprotocol(pub Option<WireFormat>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Subprotocol {
+    type Error = Infallible;
+
+    async fn from_request(req: &'r HttpRequest<'_>) -> Outcome<Self, Self::Error> {
+        let format = req.headers().get("Sec-WebSocket-Protocol")
+            .flat_map(|value| value.split(','))
+            .find_map(|name| WireFormat::from_subprotocol(name.trim()));
+        Outcome::Success(Subprotocol(format))
+    }
+}
+
+/// Websocket upgrade that confirms the subprotocol it accepted.
+pub struct Upgrade<'r> {
+    pub channel: Channel<'r>,
+    pub subprotocol: Subprotocol,
+}
+
+impl<'r, 'o: 'r> Responder<'r, 'o> for Upgrade<'o> {
+    fn respond_to(self, req: &'r HttpRequest<'_>) -> response::Result<'o> {
+        let mut response = self.channel.respond_to(req)?;
+        if let Some(format) = self.subprotocol.0 {
+            response.set_raw_header("Sec-WebSocket-Protocol", format.subprotocol());
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::ChatMessage;
+
+    use super::*;
+
+    #[test]
+    fn version_one_clients_only_get_kinds_they_know() {
+        let protocol = ClientProtocol::legacy(WireFormat::Json);
+        let room = "general".to_string();
+        let message = ChatMessage::new("hi".to_string(), "alice".to_string());
+
+        for msg in [
+            ServerMessage::NewMessage { room: room.clone(), message: message.clone() },
+            ServerMessage::UsernameChange { room: None, username: "bob".to_string() },
+            ServerMessage::UserList { room: room.clone(), users: Vec::new(), presence: BTreeMap::new() },
+        ] {
+            assert!(protocol.encode(&msg).is_some(), "{}", msg);
+        }
+        let error = ServerMessage::Error { error: ChatError::new(ErrorCode::NotFound, "gone"), request_id: None };
+        match protocol.encode(&error) {
+            Some(Message::Text(text)) => assert!(text.contains(r#""message_type":"System""#), "{}", text),
+            frame => panic!("unexpected frame {:?}", frame),
+        }
+        for msg in [
+            ServerMessage::JoinRoom { room: room.clone() },
+            ServerMessage::LeaveRoom { room: room.clone() },
+            ServerMessage::History { room: room.clone(), messages: vec![message.clone()] },
+            ServerMessage::DirectMessage { recipient: "bob".to_string(), message },
+            ServerMessage::RoomList { rooms: vec![room] },
+        ] {
+            assert!(protocol.encode(&msg).is_none(), "{}", msg);
+        }
+    }
+
+    #[test]
+    fn version_two_clients_get_rooms() {
+        let protocol = ClientProtocol::negotiate(2, &[], 1, WireFormat::Json).unwrap();
+        assert!(protocol.encode(&ServerMessage::JoinRoom { room: "general".to_string() }).is_some());
+    }
+}
@@ -0,0 +1,1019 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use rocket::tokio::{sync::Mutex, task::JoinHandle, time::Instant};
+use rocket_ws::{frame::CloseCode, Message};
+
+use common::{ChatError, ChatMessage, ClientMessage, ErrorCode, Presence, PresenceStatus, ServerMessage, WireFormat};
+
+use crate::auth::Identity;
+use crate::bus::{Event, Members};
+use crate::chat::{self, ChatRoom, ChatRoomConnection};
+use crate::config::{BusKind, ChatConfig};
+use crate::fanout::FanOut;
+use crate::limits::{ConnectionLimits, FloodGuard};
+use crate::moderation::{self, describe_duration, Moderation, Role, Sanction};
+use crate::outbox::{Outbox, WsSink};
+use crate::protocol::{self, ClientProtocol};
+use crate::storage::{self, AccountStore, Ban, BanStore, EventKind, MessageStore, StoredEvent};
+use crate::usernames::{self, UsernameError};
+
+pub const DEFAULT_ROOM: &str = "general";
+/// Close reason of connections dropped on shutdown.
+pub const SHUTDOWN_REASON: &str = "Server is restarting";
+const MAX_ROOM_NAME_LEN: usize = 64;
+/// Rooms one connection may be in at once, which also bounds how many
+/// rooms there are.
+const MAX_JOINED_ROOMS: usize = 50;
+const MAX_STATUS_TEXT_LEN: usize = 64;
+const MAX_REASON_LEN: usize = 200;
+/// Mutes are not persisted, longer ones should be bans.
+const MAX_MUTE_SECS: u64 = 7 * 86_400;
+
+
+/// State of a single websocket: its identity and the rooms it joined.
+pub struct ChatSession {
+    pub user_id: usize,
+    pub username: String,
+    /// Key stored with the messages this connection sends, which it may
+    /// edit and delete: the username of an authenticated connection, a
+    /// random key of this connection otherwise. User ids start over on
+    /// restart and anonymous names can be taken by others, so neither will do.
+    pub owner: String,
+    /// Set by the client, online until then.
+    pub presence: Presence,
+    pub outbox: Outbox,
+    pub default_room: String,
+    pub rooms: Vec<String>,
+    /// Set when the connection was authenticated; its username is fixed.
+    pub identity: Option<Identity>,
+    pub ip: Option<IpAddr>,
+    /// Set on admission, only authenticated connections get more than `User`.
+    pub role: Role,
+    /// Legacy until the client's `Hello` is answered.
+    pub protocol: Arc<ClientProtocol>,
+    pub limits: ConnectionLimits,
+    /// When anything, pongs included, was last read from the socket.
+    pub last_seen: Instant,
+}
+
+impl ChatSession {
+    /// Address of an anonymous connection. Guests get a new name with
+    /// every connection, so what is held against them is kept for it.
+    pub fn guest_address(&self) -> Option<IpAddr> {
+        self.ip.filter(|_| self.identity.is_none())
+    }
+
+    /// Who strikes and flood mutes are kept for, so that they outlast the
+    /// connection: an authenticated user, or else the address of an
+    /// anonymous one.
+    pub fn flooder(&self) -> String {
+        match self.guest_address() {
+            Some(ip) => ip.to_string(),
+            None => self.owner.clone(),
+        }
+    }
+
+    /// What rooms keep of this connection.
+    pub fn room_connection(&self) -> ChatRoomConnection {
+        ChatRoomConnection::new(
+            self.username.clone(),
+            self.owner.clone(),
+            self.presence.clone(),
+            self.outbox.clone(),
+            self.protocol.clone(),
+        )
+    }
+
+    /// Queues the message for this socket; it is written by the writer task.
+    pub fn send(&self, msg: ServerMessage) {
+        self.outbox.send(&self.protocol, &msg);
+    }
+
+    pub fn send_error(&self, error: ChatError, request_id: Option<String>) {
+        self.send(ServerMessage::Error { error, request_id });
+    }
+
+    /// Queues a close frame telling the client why it is disconnected.
+    /// Nothing is queued after it.
+    pub fn close(&self, code: CloseCode, reason: &str) {
+        self.outbox.close_with(code, reason);
+    }
+}
+
+/// Registry of named chat rooms and of every live connection. Rooms are
+/// created on first join and dropped once the last user leaves; their
+/// history stays in the store.
+pub struct ChatRooms {
+    rooms: Mutex<HashMap<String, Arc<ChatRoom>>>,
+    connections: Mutex<HashMap<usize, ChatRoomConnection>>,
+    store: Arc<dyn MessageStore>,
+    accounts: Arc<dyn AccountStore>,
+    flood_guard: FloodGuard,
+    moderation: Moderation,
+    /// Address of every live connection that has one.
+    addresses: Mutex<HashMap<usize, IpAddr>>,
+    fanout: Arc<FanOut>,
+    /// Set once shutdown started; users leaving is not announced then.
+    shutting_down: AtomicBool,
+    config: Arc<ChatConfig>,
+}
+
+impl ChatRooms {
+    pub fn new(
+        store: Arc<dyn MessageStore>,
+        accounts: Arc<dyn AccountStore>,
+        bans: Arc<dyn BanStore>,
+        fanout: Arc<FanOut>,
+        config: Arc<ChatConfig>,
+    ) -> ChatRooms {
+        ChatRooms {
+            rooms: Mutex::default(),
+            connections: Mutex::default(),
+            store,
+            accounts,
+            flood_guard: FloodGuard::new(config.clone()),
+            moderation: Moderation::new(bans, config.clone()),
+            addresses: Mutex::default(),
+            fanout,
+            shutting_down: AtomicBool::new(false),
+            config,
+        }
+    }
+
+    /// Tells every room that the server is going down. Only the first
+    /// call does anything; connections are closed by their own handlers.
+    pub async fn shutdown(&self) {
+        if self.shutting_down.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        log::info!("Shutting down, closing all connections");
+        self.fanout.publish(Event::Gone);
+        let rooms: Vec<Arc<ChatRoom>> = self.rooms.lock().await.values().cloned().collect();
+        for room in rooms {
+            room.announce_shutdown().await;
+        }
+    }
+
+    /// Agrees on a protocol with a client from its first message and
+    /// answers a `Hello` with a `Welcome`. Clients that open with anything
+    /// else (or nothing) predate the handshake and keep the legacy
+    /// protocol; their first message is given back to be handled once
+    /// connected.
+    pub async fn handshake(&self, session: &mut ChatSession, first_msg: Option<Message>) -> Result<Option<Message>, ChatError> {
+        let min_version = self.config.min_protocol_version;
+        let hello = first_msg.as_ref()
+            .and_then(protocol::decode_request)
+            .and_then(Result::ok)
+            .map(|request| request.message);
+        let (version, capabilities) = match hello {
+            Some(ClientMessage::Hello { version, capabilities }) => (version, capabilities),
+            _ if min_version > protocol::LEGACY_PROTOCOL_VERSION => {
+                return Err(protocol::unsupported_version(protocol::LEGACY_PROTOCOL_VERSION, min_version));
+            },
+            _ => return Ok(first_msg),
+        };
+
+        let protocol = ClientProtocol::negotiate(version, &capabilities, min_version, session.protocol.format)?;
+        let welcome = ServerMessage::Welcome {
+            version: protocol.version,
+            capabilities: protocol.capabilities(),
+        };
+        session.protocol = Arc::new(protocol);
+        session.send(welcome);
+        Ok(None)
+    }
+
+    /// Gives authenticated connections the role of their username and
+    /// turns away banned users and addresses. Staff is spared address bans
+    /// so that a shared address cannot lock out whoever would lift them.
+    pub async fn admit(&self, session: &mut ChatSession) -> Result<(), ChatError> {
+        let username = session.identity.as_ref().map(|identity| identity.username.as_str());
+        if username.is_some() {
+            session.role = self.moderation.role_of(&session.username);
+        }
+        let ip = session.ip.filter(|_| session.role < Role::Moderator);
+        self.moderation.check_ban(username, ip).await
+    }
+
+    pub fn hello_timeout(&self) -> Duration {
+        Duration::from_millis(self.config.hello_timeout_ms)
+    }
+
+    /// Websocket settings that bound what a client may send.
+    pub fn ws_config(&self) -> rocket_ws::Config {
+        rocket_ws::Config {
+            max_frame_size: Some(self.config.max_frame_size),
+            max_message_size: Some(self.config.max_frame_size),
+            ..rocket_ws::Config::default()
+        }
+    }
+
+    /// State of a new connection, with fresh rate limits. Anonymous users
+    /// are named by their user id; ids are counted per instance, so with
+    /// a shared bus the names also carry the instance's tag.
+    pub fn open_session(
+        &self,
+        user_id: usize,
+        outbox: Outbox,
+        default_room: String,
+        identity: Option<Identity>,
+        ip: Option<IpAddr>,
+        format: WireFormat,
+    ) -> ChatSession {
+        let (username, owner) = match &identity {
+            Some(identity) => (identity.username.clone(), format!("{}{}", chat::USER_OWNER_PREFIX, identity.username)),
+            None => {
+                let username = match self.config.bus {
+                    BusKind::Memory => format!("user #{}", user_id),
+                    BusKind::Redis => format!("user #{}-{}", user_id, self.fanout.tag()),
+                };
+                (username, format!("connection:{:016x}", rand::random::<u64>()))
+            },
+        };
+        ChatSession {
+            user_id,
+            username,
+            owner,
+            presence: Presence::default(),
+            outbox,
+            default_room,
+            rooms: Vec::new(),
+            identity,
+            ip,
+            role: Role::User,
+            protocol: Arc::new(ClientProtocol::legacy(format)),
+            limits: self.flood_guard.connection_limits(ip),
+            last_seen: Instant::now(),
+        }
+    }
+
+    /// How often connections are pinged. `None` if heartbeats are off.
+    pub fn ping_interval(&self) -> Option<Duration> {
+        match self.config.ping_interval {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+
+    /// Pings the client, or closes its connection if it has not answered
+    /// the last `missed_pings` pings. Returns whether it is still alive.
+    pub fn heartbeat(&self, session: &ChatSession) -> bool {
+        let deadline = Duration::from_secs(self.config.ping_interval) * self.config.missed_pings;
+        if session.last_seen.elapsed() > deadline {
+            log::info!("User {} missed {} heartbeats, disconnecting", session.user_id, self.config.missed_pings);
+            session.outbox.close_now(CloseCode::Away, "Missed heartbeats");
+            return false;
+        }
+        session.outbox.ping();
+        true
+    }
+
+    /// Starts the outbound queue of a new connection and its writer task.
+    pub fn open_outbox(&self, user_id: usize, sink: WsSink) -> (Outbox, JoinHandle<()>) {
+        Outbox::spawn(user_id, sink, &self.config)
+    }
+
+    /// Registers a new connection and joins its default room. An
+    /// authenticated user takes their name back from anonymous users with
+    /// a look-alike name, on every instance.
+    pub async fn connect(&self, session: &mut ChatSession) {
+        {
+            let mut conns = self.connections.lock().await;
+            if session.identity.is_some() {
+                evict_look_alikes(&conns, &session.username);
+            }
+            conns.insert(session.user_id, session.room_connection());
+        }
+        if session.identity.is_some() {
+            self.fanout.publish(Event::Claim { username: session.username.clone() });
+        }
+        if let Some(ip) = session.ip {
+            self.addresses.lock().await.insert(session.user_id, ip);
+        }
+        if let Err(err) = self.join(session, session.default_room.clone()).await {
+            session.send_error(err, None);
+        }
+    }
+
+    pub async fn disconnect(&self, session: &mut ChatSession) {
+        self.leave_all(session).await;
+        self.connections.lock().await.remove(&session.user_id);
+        self.addresses.lock().await.remove(&session.user_id);
+        self.publish_members().await;
+    }
+
+    pub fn fanout(&self) -> &FanOut {
+        &self.fanout
+    }
+
+    /// Who is connected to this instance.
+    async fn members(&self) -> Members {
+        let (users, presence) = {
+            let conns = self.connections.lock().await;
+            let users = conns.values().map(|conn| conn.username.clone()).collect();
+            let presence = chat::merge_presence(conns.values().map(|conn| (conn.username.clone(), conn.presence.clone())));
+            (users, presence)
+        };
+        let rooms: Vec<Arc<ChatRoom>> = self.rooms.lock().await.values().cloned().collect();
+        let mut members = Members { users, presence, ..Members::default() };
+        for room in rooms {
+            members.rooms.insert(room.name.clone(), room.usernames().await);
+        }
+        members
+    }
+
+    /// Tells the other instances who is connected to this one.
+    pub async fn publish_members(&self) {
+        self.fanout.publish(Event::Members { members: self.members().await });
+    }
+
+    /// Delivers a message another instance published to a room, if any
+    /// of its users are connected here.
+    pub async fn deliver_to_room(&self, room_name: &str, msg: ServerMessage) {
+        if let Some(room) = self.get(room_name).await {
+            room.deliver(msg).await;
+        }
+    }
+
+    /// Delivers a direct message another instance published to every
+    /// connection of the user here.
+    pub async fn deliver_to_user(&self, username: &str, msg: &ServerMessage) {
+        let conns = self.connections.lock().await;
+        for conn in conns.values().filter(|conn| conn.username == username) {
+            conn.send(msg);
+        }
+    }
+
+    /// Closes the anonymous connections here whose name looks like that of
+    /// a user who connected to another instance.
+    pub async fn claim_username(&self, username: &str) {
+        evict_look_alikes(&*self.connections.lock().await, username);
+    }
+
+    /// Sends the users of these rooms a fresh list, after users on
+    /// other instances came or went.
+    pub async fn refresh_user_lists(&self, room_names: BTreeSet<String>) {
+        for room_name in room_names {
+            if let Some(room) = self.get(&room_name).await {
+                room.broadcast_users_list().await;
+            }
+        }
+    }
+
+    pub async fn get(&self, name: &str) -> Option<Arc<ChatRoom>> {
+        self.rooms.lock().await.get(name).cloned()
+    }
+
+    /// Rooms with users on any instance.
+    pub async fn list(&self) -> Vec<String> {
+        let mut rooms = self.fanout.remote_rooms();
+        rooms.extend(self.rooms.lock().await.keys().cloned());
+        rooms.into_iter().collect()
+    }
+
+    async fn remove_if_empty(&self, name: &str) {
+        let mut rooms = self.rooms.lock().await;
+        let is_empty = match rooms.get(name) {
+            Some(room) => room.is_empty().await,
+            _ => false,
+        };
+        if is_empty {
+            log::info!("Removing empty room {}", name);
+            rooms.remove(name);
+        }
+    }
+
+    /// Latest chat messages of a room, whether it is live or not.
+    pub async fn history(&self, room_name: &str, limit: usize) -> Vec<StoredEvent> {
+        let (store, room) = (self.store.clone(), room_name.to_string());
+        match storage::run_blocking(move || store.recent(&room, limit)).await {
+            Ok(events) => events,
+            Err(err) => {
+                log::warn!("Cannot load history of room {}: {}", room_name, err);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Checks the text of a chat or direct message against the length
+    /// limit. Control characters other than line breaks and tabs are
+    /// rejected so messages cannot mess with how clients render them.
+    fn check_text(&self, text: &str) -> Result<(), ChatError> {
+        if text.trim().is_empty() {
+            return Err(ChatError::new(ErrorCode::InvalidRequest, "Message is empty"));
+        }
+        if text.chars().count() > self.config.max_message_length {
+            return Err(ChatError::new(
+                ErrorCode::InvalidRequest,
+                format!("Message is longer than {} characters", self.config.max_message_length),
+            ));
+        }
+        if text.chars().any(|c| c.is_control() && !matches!(c, '\n' | '\r' | '\t')) {
+            return Err(ChatError::new(ErrorCode::InvalidRequest, "Message contains control characters"));
+        }
+        Ok(())
+    }
+
+    pub fn is_valid_room_name(name: &str) -> bool {
+        !name.trim().is_empty() && name.chars().count() <= MAX_ROOM_NAME_LEN
+    }
+
+    pub async fn join(&self, session: &mut ChatSession, room_name: String) -> Result<(), ChatError> {
+        if !Self::is_valid_room_name(&room_name) {
+            return Err(ChatError::new(ErrorCode::InvalidRequest, "Invalid room name"));
+        }
+        if session.rooms.contains(&room_name) {
+            return Ok(());
+        }
+        if session.rooms.len() >= MAX_JOINED_ROOMS {
+            return Err(ChatError::new(
+                ErrorCode::InvalidRequest,
+                format!("You cannot be in more than {} rooms at once", MAX_JOINED_ROOMS),
+            ));
+        }
+        session.send(ServerMessage::JoinRoom { room: room_name.clone() });
+        // Reserve while holding the registry lock so a concurrent leave
+        // cannot drop the room before we are in it.
+        let room = {
+            let mut rooms = self.rooms.lock().await;
+            let room = rooms.entry(room_name.clone())
+                .or_insert_with(|| {
+                    log::info!("Creating room {}", room_name);
+                    Arc::new(ChatRoom::new(room_name.clone(), self.store.clone(), self.fanout.clone(), self.config.clone()))
+                })
+                .clone();
+            room.reserve();
+            room
+        };
+        room.insert(session.user_id, session.room_connection()).await;
+        session.rooms.push(room_name);
+        room.announce_join(session.user_id, session.username.clone()).await;
+        self.publish_members().await;
+        Ok(())
+    }
+
+    pub async fn leave(&self, session: &mut ChatSession, room_name: &str) -> Result<(), ChatError> {
+        if !session.rooms.iter().any(|r| r == room_name) {
+            return Err(not_in_room(room_name));
+        }
+        session.rooms.retain(|r| r != room_name);
+        if let Some(room) = self.get(room_name).await {
+            if self.shutting_down.load(Ordering::Relaxed) {
+                room.remove(session.user_id).await;
+            } else {
+                room.flush(session.user_id).await;
+            }
+        }
+        self.remove_if_empty(room_name).await;
+        self.publish_members().await;
+        session.send(ServerMessage::LeaveRoom { room: room_name.to_string() });
+        Ok(())
+    }
+
+    pub async fn leave_all(&self, session: &mut ChatSession) {
+        for room_name in session.rooms.clone() {
+            // Only joined rooms are left, which cannot fail.
+            let _ = self.leave(session, &room_name).await;
+        }
+    }
+
+    /// Resolves the room a client message targets, falling back to the
+    /// room the socket was opened on. Only joined rooms are returned.
+    async fn target_room(&self, session: &ChatSession, room: Option<String>) -> Result<Arc<ChatRoom>, ChatError> {
+        let room_name = room.unwrap_or_else(|| session.default_room.clone());
+        if !session.rooms.contains(&room_name) {
+            return Err(not_in_room(&room_name));
+        }
+        self.get(&room_name).await.ok_or_else(|| not_in_room(&room_name))
+    }
+
+    /// Renames an anonymous user. The name must follow the naming rules
+    /// and must not look like the name of another live user or an account.
+    pub async fn change_username(&self, session: &mut ChatSession, new_username: String) -> Result<(), ChatError> {
+        if session.identity.is_some() {
+            return Err(ChatError::new(ErrorCode::Forbidden, "Username is bound to your account"));
+        }
+        let new_username = usernames::normalize(&new_username).map_err(username_error)?;
+        let skeleton = usernames::skeleton_key(&new_username);
+        let accounts = self.accounts.clone();
+        match storage::run_blocking(move || accounts.account_by_skeleton(&skeleton)).await {
+            Ok(None) => {},
+            Ok(Some(_)) => return Err(username_error(UsernameError::Taken)),
+            Err(err) => {
+                log::warn!("Cannot look up account {}: {}", new_username, err);
+                return Err(ChatError::new(ErrorCode::Internal, "Cannot check username, try again later"));
+            }
+        }
+        if self.moderation.is_staff_name(&new_username) {
+            return Err(username_error(UsernameError::Reserved));
+        }
+        match self.moderation.check_ban(Some(&new_username), None).await {
+            Ok(()) => {},
+            Err(err) if err.code == ErrorCode::Internal => return Err(err),
+            Err(_) => return Err(username_error(UsernameError::Banned)),
+        }
+
+        {
+            // Checked and claimed under one lock so two users cannot take the same name.
+            let mut conns = self.connections.lock().await;
+            let is_taken = conns.iter()
+                .any(|(id, conn)| *id != session.user_id && usernames::is_confusable(&conn.username, &new_username))
+                || self.fanout.remote_usernames().iter()
+                    .any(|username| usernames::is_confusable(username, &new_username));
+            if is_taken {
+                return Err(username_error(UsernameError::Taken));
+            }
+            if let Some(conn) = conns.get_mut(&session.user_id) {
+                conn.username = new_username.clone();
+            }
+        }
+        session.username = new_username.clone();
+        session.send(ServerMessage::UsernameChange { room: None, username: new_username.clone() });
+        for room_name in session.rooms.iter() {
+            if let Some(room) = self.get(room_name).await {
+                room.change_username(session.user_id, new_username.clone()).await;
+            }
+        }
+        self.publish_members().await;
+        Ok(())
+    }
+
+    /// Sets the presence the connection shows in user lists. A blank
+    /// status text clears it.
+    pub async fn set_presence(&self, session: &mut ChatSession, status: PresenceStatus, text: Option<String>) -> Result<(), ChatError> {
+        let text = clean_text(text, MAX_STATUS_TEXT_LEN, "Status")?;
+        let presence = Presence { status, text };
+        if presence == session.presence {
+            return Ok(());
+        }
+
+        if let Some(conn) = self.connections.lock().await.get_mut(&session.user_id) {
+            conn.presence = presence.clone();
+        }
+        session.presence = presence.clone();
+        for room_name in session.rooms.iter() {
+            if let Some(room) = self.get(room_name).await {
+                room.set_presence(session.user_id, presence.clone()).await;
+            }
+        }
+        self.publish_members().await;
+        Ok(())
+    }
+
+    /// Delivers a message to every connection of the recipient, on any
+    /// instance, and echoes it back to the sender. Nothing is sent if the
+    /// recipient is offline.
+    pub async fn direct_message(&self, session: &ChatSession, recipient: String, text: String) -> Result<(), ChatError> {
+        let chat_msg = ChatMessage::new(text, session.username.clone());
+
+        let conns = self.connections.lock().await;
+        let recipients: Vec<&ChatRoomConnection> = conns.iter()
+            .filter(|(id, conn)| **id != session.user_id && conn.username == recipient)
+            .map(|(_, conn)| conn)
+            .collect();
+        let is_remote = self.fanout.remote_usernames().contains(&recipient);
+        if recipients.is_empty() && !is_remote && recipient != session.username {
+            return Err(ChatError::new(ErrorCode::NotFound, format!("{} is not online", recipient)));
+        }
+
+        let direct_msg = ServerMessage::DirectMessage { recipient: recipient.clone(), message: chat_msg };
+        if is_remote {
+            self.fanout.publish(Event::Direct { username: recipient, message: direct_msg.clone() });
+        }
+        for conn in recipients {
+            conn.send(&direct_msg);
+        }
+        session.send(direct_msg);
+        Ok(())
+    }
+
+    /// Rooms the user joined on any instance.
+    async fn rooms_of(&self, username: &str) -> BTreeSet<String> {
+        let mut room_names = self.fanout.remote_rooms_of(username);
+        let rooms: Vec<Arc<ChatRoom>> = self.rooms.lock().await.values().cloned().collect();
+        for room in rooms {
+            if room.usernames().await.iter().any(|user| user == username) {
+                room_names.insert(room.name.clone());
+            }
+        }
+        room_names
+    }
+
+    async fn is_online(&self, username: &str) -> bool {
+        self.connections.lock().await.values().any(|conn| conn.username == username)
+            || self.fanout.remote_usernames().iter().any(|user| user == username)
+    }
+
+    /// Stores a system message of a room and sends it to the room's users
+    /// on every instance, whether or not it is live here.
+    async fn announce(&self, room_name: &str, text: String) {
+        if let Some(room) = self.get(room_name).await {
+            return room.announce(text).await;
+        }
+        let mut message = ChatMessage::new(text, "system".to_string());
+        let event = StoredEvent {
+            room: room_name.to_string(),
+            kind: EventKind::System,
+            message: message.clone(),
+            owner: None,
+        };
+        let store = self.store.clone();
+        match storage::run_blocking(move || store.append(&event)).await {
+            Ok(id) => message.id = Some(id),
+            Err(err) => log::warn!("Cannot persist system event in room {}: {}", room_name, err),
+        }
+        let msg = ServerMessage::System { room: Some(room_name.to_string()), message };
+        self.fanout.publish(Event::Room { room: room_name.to_string(), message: msg });
+    }
+
+    /// Announces what a moderator did in every room of the user, then has
+    /// every instance apply it. The moderator is told directly if they
+    /// share none of these rooms.
+    async fn moderate(&self, session: &ChatSession, username: String, sanction: Sanction, reason: Option<String>, announcement: String) {
+        log::info!("{} moderated {}: {:?}", session.username, username, sanction);
+        let text = with_reason(announcement, reason.as_deref());
+        let room_names = self.rooms_of(&username).await;
+        for room_name in room_names.iter() {
+            self.announce(room_name, text.clone()).await;
+        }
+        if !room_names.iter().any(|room_name| session.rooms.contains(room_name)) {
+            session.send(ServerMessage::system(None, text));
+        }
+        self.apply_sanction(&username, &sanction, &session.username, reason.as_deref()).await;
+        self.fanout.publish(Event::Moderate { username, sanction, by: session.username.clone(), reason });
+    }
+
+    /// Applies a sanction to the user's connections on this instance and
+    /// tells them why. Kicked and banned connections are closed.
+    /// Anonymous connections are also muted or banned by address, since a
+    /// guest's name changes when they reconnect.
+    pub async fn apply_sanction(&self, username: &str, sanction: &Sanction, by: &str, reason: Option<&str>) {
+        let notice = match sanction {
+            Sanction::Kick => format!("You were kicked by {}", by),
+            Sanction::Ban { expires_at: Some(expires_at), .. } => {
+                format!("You were banned by {} until {} UTC", by, expires_at.format("%Y-%m-%d %H:%M"))
+            },
+            Sanction::Ban { expires_at: None, .. } => format!("You were banned by {} for good", by),
+            Sanction::Mute { seconds: 0 } => format!("{} lifted your mute", by),
+            Sanction::Mute { seconds } => format!("You were muted by {} for {}", by, describe_duration(*seconds)),
+        };
+        let notice = ServerMessage::system(None, with_reason(notice, reason));
+
+        let mut bans = Vec::new();
+        let mut guest_addresses = Vec::new();
+        {
+            let conns = self.connections.lock().await;
+            let addresses = self.addresses.lock().await;
+            for (user_id, conn) in conns.iter().filter(|(_, conn)| conn.username == username) {
+                conn.send(&notice);
+                let guest_address = addresses.get(user_id).copied().filter(|_| !conn.is_authenticated());
+                guest_addresses.extend(guest_address);
+                match sanction {
+                    Sanction::Kick => conn.outbox.close_with(CloseCode::Policy, "Kicked by a moderator"),
+                    Sanction::Ban { ip, expires_at } => {
+                        let address = if *ip { addresses.get(user_id).copied() } else { guest_address };
+                        if let Some(address) = address {
+                            bans.push(Ban {
+                                username: username.to_string(),
+                                skeleton: Some(usernames::skeleton_key(username)),
+                                ip: Some(address.to_string()),
+                                reason: reason.map(str::to_string),
+                                banned_by: by.to_string(),
+                                created_at: Utc::now().naive_utc(),
+                                expires_at: *expires_at,
+                            });
+                        }
+                        conn.outbox.close_with(CloseCode::Policy, "Banned by a moderator");
+                    },
+                    Sanction::Mute { .. } => {},
+                }
+            }
+        }
+        if let Sanction::Mute { seconds } = sanction {
+            self.moderation.mute(username, &guest_addresses, *seconds);
+        }
+        for ban in bans {
+            // A failure is logged; the user is still disconnected.
+            let _ = self.moderation.ban(&ban).await;
+        }
+    }
+
+    pub async fn kick(&self, session: &ChatSession, username: String, reason: Option<String>) -> Result<(), ChatError> {
+        self.moderation.authorize(session.role, &username)?;
+        let reason = clean_text(reason, MAX_REASON_LEN, "Reason")?;
+        if !self.is_online(&username).await {
+            return Err(ChatError::new(ErrorCode::NotFound, format!("{} is not online", username)));
+        }
+        let announcement = format!("{} was kicked by {}", username, session.username);
+        self.moderate(session, username, Sanction::Kick, reason, announcement).await;
+        Ok(())
+    }
+
+    /// Bans a user, online or not, for `duration` seconds or for good.
+    /// With `ip` the addresses they are connected from are banned as well.
+    pub async fn ban(
+        &self,
+        session: &ChatSession,
+        username: String,
+        duration: Option<u64>,
+        ip: bool,
+        reason: Option<String>,
+    ) -> Result<(), ChatError> {
+        self.moderation.authorize(session.role, &username)?;
+        let reason = clean_text(reason, MAX_REASON_LEN, "Reason")?;
+        if ip && !self.is_online(&username).await {
+            return Err(ChatError::new(
+                ErrorCode::NotFound,
+                format!("{} is not online, so their address is unknown; ban it with BanIp", username),
+            ));
+        }
+        let now = Utc::now().naive_utc();
+        let expires_at = ban_expiry(now, duration)?;
+        self.moderation.ban(&Ban {
+            username: username.clone(),
+            skeleton: Some(usernames::skeleton_key(&username)),
+            ip: None,
+            reason: reason.clone(),
+            banned_by: session.username.clone(),
+            created_at: now,
+            expires_at,
+        }).await?;
+
+        let length = match duration {
+            Some(secs) => format!("for {}", describe_duration(secs)),
+            None => "for good".to_string(),
+        };
+        let announcement = format!("{} was banned by {} {}", username, session.username, length);
+        self.moderate(session, username, Sanction::Ban { ip, expires_at }, reason, announcement).await;
+        Ok(())
+    }
+
+    /// Bans an address for `duration` seconds or for good, and closes the
+    /// connections from it on every instance but those of staff.
+    pub async fn ban_ip(&self, session: &ChatSession, ip: String, duration: Option<u64>, reason: Option<String>) -> Result<(), ChatError> {
+        moderation::require_moderator(session.role)?;
+        let ip = parse_ip(&ip)?;
+        let reason = clean_text(reason, MAX_REASON_LEN, "Reason")?;
+        let now = Utc::now().naive_utc();
+        let expires_at = ban_expiry(now, duration)?;
+        self.moderation.ban(&Ban {
+            username: ip.to_string(),
+            skeleton: None,
+            ip: Some(ip.to_string()),
+            reason: reason.clone(),
+            banned_by: session.username.clone(),
+            created_at: now,
+            expires_at,
+        }).await?;
+        log::info!("{} banned address {}", session.username, ip);
+        let length = match duration {
+            Some(secs) => format!("for {}", describe_duration(secs)),
+            None => "for good".to_string(),
+        };
+        session.send(ServerMessage::system(None, format!("Banned {} {}", ip, length)));
+        self.close_address(ip, &session.username, reason.as_deref()).await;
+        self.fanout.publish(Event::BanAddress { ip, by: session.username.clone(), reason });
+        Ok(())
+    }
+
+    /// Closes the connections from a banned address on this instance,
+    /// sparing staff as `admit` does.
+    pub async fn close_address(&self, ip: IpAddr, by: &str, reason: Option<&str>) {
+        let notice = ServerMessage::system(None, with_reason(format!("Your address was banned by {}", by), reason));
+        let conns = self.connections.lock().await;
+        let addresses = self.addresses.lock().await;
+        let banned = addresses.iter()
+            .filter(|(_, address)| **address == ip)
+            .filter_map(|(user_id, _)| conns.get(user_id))
+            .filter(|conn| !conn.is_authenticated() || self.moderation.role_of(&conn.username) < Role::Moderator);
+        for conn in banned {
+            conn.send(&notice);
+            conn.outbox.close_with(CloseCode::Policy, "Banned by a moderator");
+        }
+    }
+
+    pub async fn unban(&self, session: &ChatSession, username: String) -> Result<(), ChatError> {
+        self.moderation.authorize(session.role, &username)?;
+        if self.moderation.unban(&username).await? == 0 {
+            return Err(ChatError::new(ErrorCode::NotFound, format!("{} is not banned", username)));
+        }
+        log::info!("{} lifted the bans of {}", session.username, username);
+        session.send(ServerMessage::system(None, format!("Lifted the bans of {}", username)));
+        Ok(())
+    }
+
+    pub async fn unban_ip(&self, session: &ChatSession, ip: String) -> Result<(), ChatError> {
+        moderation::require_moderator(session.role)?;
+        let ip = parse_ip(&ip)?;
+        if self.moderation.unban_ip(ip).await? == 0 {
+            return Err(ChatError::new(ErrorCode::NotFound, format!("{} is not banned", ip)));
+        }
+        log::info!("{} lifted the bans of address {}", session.username, ip);
+        session.send(ServerMessage::system(None, format!("Lifted the bans of {}", ip)));
+        Ok(())
+    }
+
+    /// Keeps a user, online or not, from posting for `duration` seconds.
+    /// A `duration` of `0` lifts the mute.
+    pub async fn mute(&self, session: &ChatSession, username: String, duration: u64, reason: Option<String>) -> Result<(), ChatError> {
+        self.moderation.authorize(session.role, &username)?;
+        let reason = clean_text(reason, MAX_REASON_LEN, "Reason")?;
+        if duration > MAX_MUTE_SECS {
+            return Err(ChatError::new(
+                ErrorCode::InvalidRequest,
+                format!("Mutes last at most {}, ban instead", describe_duration(MAX_MUTE_SECS)),
+            ));
+        }
+        let announcement = match duration {
+            0 => format!("{} lifted the mute of {}", session.username, username),
+            secs => format!("{} was muted by {} for {}", username, session.username, describe_duration(secs)),
+        };
+        self.moderate(session, username, Sanction::Mute { seconds: duration }, reason, announcement).await;
+        Ok(())
+    }
+
+    /// Handles a client message within its rate limits, unless the user
+    /// is muted. Failures are reported to this client only.
+    pub async fn handle_chat_msg(&self, session: &mut ChatSession, msg: Message) {
+        let request = match protocol::decode_request(&msg) {
+            Some(Ok(request)) => request,
+            None => return,
+            Some(Err((err, request_id))) => {
+                log::warn!("Cannot parse message from user {}: {}", session.user_id, err);
+                return session.send_error(err, request_id);
+            }
+        };
+        let request_type = request.message.kind();
+        let flooder = session.flooder();
+        let allowed = self.moderation.check(&session.username, session.guest_address(), &request.message)
+            .and_then(|()| self.flood_guard.check(&mut session.limits, &flooder, &request.message));
+        let result = match allowed {
+            Ok(()) => self.dispatch(session, request.message).await,
+            Err(err) => Err(err),
+        };
+        if let Err(mut err) = result {
+            log::warn!("Request {:?} of user {} failed: {}", request_type, session.user_id, err);
+            err.request_type = Some(request_type);
+            session.send_error(err, request.request_id);
+        }
+    }
+
+    async fn dispatch(&self, session: &mut ChatSession, msg: ClientMessage) -> Result<(), ChatError> {
+        match msg {
+            ClientMessage::NewMessage { room, text, reply_to } => {
+                self.check_text(&text)?;
+                let room = self.target_room(session, room).await?;
+                room.broadcast_message(text, reply_to, session.user_id).await
+            },
+            ClientMessage::UsernameChange { username } => {
+                self.change_username(session, username).await
+            },
+            ClientMessage::UserList { room } => {
+                let room = self.target_room(session, room).await?;
+                room.broadcast_users_list().await;
+                Ok(())
+            },
+            ClientMessage::Presence { status, text } => {
+                self.set_presence(session, status, text).await
+            },
+            ClientMessage::JoinRoom { room } => {
+                self.join(session, room).await
+            },
+            ClientMessage::LeaveRoom { room } => {
+                self.leave(session, &room).await
+            },
+            ClientMessage::DirectMessage { recipient, text } => {
+                self.check_text(&text)?;
+                self.direct_message(session, recipient, text).await
+            },
+            ClientMessage::EditMessage { room, message_id, text } => {
+                self.check_text(&text)?;
+                let room = self.target_room(session, room).await?;
+                room.edit_message(session.user_id, message_id, text).await
+            },
+            ClientMessage::DeleteMessage { room, message_id } => {
+                let room = self.target_room(session, room).await?;
+                room.delete_message(session.user_id, message_id).await
+            },
+            ClientMessage::AddReaction { room, message_id, emoji } => {
+                let room = self.target_room(session, room).await?;
+                room.react(session.user_id, message_id, emoji, true).await
+            },
+            ClientMessage::RemoveReaction { room, message_id, emoji } => {
+                let room = self.target_room(session, room).await?;
+                room.react(session.user_id, message_id, emoji, false).await
+            },
+            ClientMessage::Thread { room, message_id } => {
+                let room = self.target_room(session, room).await?;
+                room.send_thread(session.user_id, message_id).await
+            },
+            ClientMessage::Typing { room, typing } => {
+                let room = self.target_room(session, room).await?;
+                room.set_typing(session.user_id, typing).await;
+                Ok(())
+            },
+            ClientMessage::Read { room, message_id } => {
+                let room = self.target_room(session, room).await?;
+                room.mark_read(session.user_id, message_id).await
+            },
+            ClientMessage::RoomList => {
+                session.send(ServerMessage::RoomList { rooms: self.list().await });
+                Ok(())
+            },
+            ClientMessage::Kick { username, reason } => {
+                self.kick(session, username, reason).await
+            },
+            ClientMessage::Ban { username, duration, ip, reason } => {
+                self.ban(session, username, duration, ip, reason).await
+            },
+            ClientMessage::Unban { username } => {
+                self.unban(session, username).await
+            },
+            ClientMessage::BanIp { ip, duration, reason } => {
+                self.ban_ip(session, ip, duration, reason).await
+            },
+            ClientMessage::UnbanIp { ip } => {
+                self.unban_ip(session, ip).await
+            },
+            ClientMessage::Mute { username, duration, reason } => {
+                self.mute(session, username, duration, reason).await
+            },
+            ClientMessage::Hello { .. } => {
+                Err(ChatError::new(ErrorCode::Unsupported, "Hello is only accepted as the first message"))
+            },
+        }
+    }
+}
+
+/// Closes the anonymous connections whose name looks like `username`.
+/// They took it before its user authenticated, e.g. before it was registered.
+fn evict_look_alikes(conns: &HashMap<usize, ChatRoomConnection>, username: &str) {
+    let look_alikes = conns.values()
+        .filter(|conn| !conn.is_authenticated() && usernames::is_confusable(&conn.username, username));
+    for conn in look_alikes {
+        log::info!("{} connected, closing an anonymous connection named {}", username, conn.username);
+        conn.send(&ServerMessage::system(None, format!("{} belongs to a user who just connected", conn.username)));
+        conn.outbox.close_with(CloseCode::Policy, "Username belongs to another user");
+    }
+}
+
+/// When a ban of `duration` seconds from `now` ends; `None` for good.
+fn ban_expiry(now: NaiveDateTime, duration: Option<u64>) -> Result<Option<NaiveDateTime>, ChatError> {
+    match duration {
+        Some(0) => Err(ChatError::new(ErrorCode::InvalidRequest, "Ban duration must be positive")),
+        Some(secs) => {
+            let expires_at = i64::try_from(secs).ok()
+                .and_then(TimeDelta::try_seconds)
+                .and_then(|duration| now.checked_add_signed(duration));
+            Ok(Some(expires_at.ok_or_else(|| ChatError::new(ErrorCode::InvalidRequest, "Ban duration is too long"))?))
+        },
+        None => Ok(None),
+    }
+}
+
+fn parse_ip(ip: &str) -> Result<IpAddr, ChatError> {
+    ip.trim().parse().map_err(|_| ChatError::new(ErrorCode::InvalidRequest, format!("{} is not an IP address", ip)))
+}
+
+fn not_in_room(room_name: &str) -> ChatError {
+    ChatError::new(ErrorCode::NotInRoom, format!("You have not joined room {}", room_name))
+}
+
+/// Trims a free text like a status or a reason. Blank text is dropped,
+/// long text and control characters are rejected.
+fn clean_text(text: Option<String>, max_len: usize, what: &str) -> Result<Option<String>, ChatError> {
+    let text = match text.map(|text| text.trim().to_string()).filter(|text| !text.is_empty()) {
+        Some(text) => text,
+        None => return Ok(None),
+    };
+    if text.chars().count() > max_len {
+        return Err(ChatError::new(
+            ErrorCode::InvalidRequest,
+            format!("{} is longer than {} characters", what, max_len),
+        ));
+    }
+    if text.chars().any(char::is_control) {
+        return Err(ChatError::new(ErrorCode::InvalidRequest, format!("{} contains control characters", what)));
+    }
+    Ok(Some(text))
+}
+
+fn with_reason(text: String, reason: Option<&str>) -> String {
+    match reason {
+        Some(reason) => format!("{}: {}", text, reason),
+        None => text,
+    }
+}
+
+fn username_error(err: UsernameError) -> ChatError {
+    ChatError::new(ErrorCode::InvalidUsername, format!("Cannot change username: {}", err))
+}
@@ -0,0 +1,228 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt,
+    sync::Arc,
+};
+
+use chrono::NaiveDateTime;
+use rocket::tokio::task;
+
+use common::ChatMessage;
+
+use crate::config::{ChatConfig, StorageKind};
+
+mod memory;
+mod sqlite;
+
+pub use memory::MemoryStore;
+pub use sqlite::SqliteStore;
+
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EventKind {
+    Message,
+    System,
+}
+
+impl EventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::Message => "message",
+            EventKind::System => "system",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<EventKind> {
+        match kind {
+            "message" => Some(EventKind::Message),
+            "system" => Some(EventKind::System),
+            _ => None,
+        }
+    }
+}
+
+/// Usernames that reacted to a message, grouped by emoji.
+pub type MessageReactions = BTreeMap<String, BTreeSet<String>>;
+
+/// A chat message or system event as it was broadcast to a room.
+#[derive(Clone)]
+pub struct StoredEvent {
+    pub room: String,
+    pub kind: EventKind,
+    pub message: ChatMessage,
+    /// Key of whoever may edit or delete the message, see
+    /// `ChatSession::owner`. `None` for system events.
+    pub owner: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct StorageError(pub String);
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "storage error: {}", self.0)
+    }
+}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(err: rusqlite::Error) -> StorageError {
+        StorageError(err.to_string())
+    }
+}
+
+/// Runs a store call on the blocking thread pool, so a slow disk does not
+/// hold up the async workers.
+pub async fn run_blocking<T, F>(call: F) -> Result<T, StorageError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, StorageError> + Send + 'static,
+{
+    task::spawn_blocking(call).await
+        .map_err(|err| StorageError(format!("store call failed: {}", err)))?
+}
+
+/// Backend that keeps the history of every room.
+pub trait MessageStore: Send + Sync {
+    /// Stores a new event and returns the id assigned to its message.
+    fn append(&self, event: &StoredEvent) -> Result<u64, StorageError>;
+
+    fn get(&self, id: u64) -> Result<Option<StoredEvent>, StorageError>;
+
+    /// Overwrites the text and flags of an already stored event. The
+    /// reactions to a deleted message are dropped.
+    fn update(&self, event: &StoredEvent) -> Result<(), StorageError>;
+
+    /// Returns all replies to a message, oldest first.
+    fn replies(&self, message_id: u64) -> Result<Vec<StoredEvent>, StorageError>;
+
+    /// Returns up to `limit` latest chat messages of the room, oldest
+    /// first. System events are left out.
+    fn recent(&self, room: &str, limit: usize) -> Result<Vec<StoredEvent>, StorageError>;
+
+    /// Adds a user's reaction to a message. Returns `false` if it was
+    /// there already.
+    fn add_reaction(&self, message_id: u64, emoji: &str, username: &str) -> Result<bool, StorageError>;
+
+    /// Removes a user's reaction to a message. Returns `false` if there
+    /// was none.
+    fn remove_reaction(&self, message_id: u64, emoji: &str, username: &str) -> Result<bool, StorageError>;
+
+    /// Returns the reactions to these messages by message id, leaving out
+    /// those without any.
+    fn reactions(&self, message_ids: &[u64]) -> Result<HashMap<u64, MessageReactions>, StorageError>;
+
+    /// Moves the user's read marker of the room forward to `message_id`.
+    /// Returns where it was, `0` if nowhere, or `None` if it was there or
+    /// further already.
+    fn mark_read(&self, room: &str, username: &str, message_id: u64) -> Result<Option<u64>, StorageError>;
+
+    /// Returns who wrote the chat messages of the room with ids above
+    /// `after` and up to `up_to`.
+    fn authors(&self, room: &str, after: u64, up_to: u64) -> Result<BTreeSet<String>, StorageError>;
+
+    /// Returns the id of the last message every user read in the room,
+    /// by username.
+    fn read_markers(&self, room: &str) -> Result<BTreeMap<String, u64>, StorageError>;
+
+    /// Hands the reactions and read marker of a user in the room over to
+    /// the name they changed to.
+    fn rename_user(&self, room: &str, old_username: &str, new_username: &str) -> Result<(), StorageError>;
+}
+
+/// A registered user.
+#[derive(Clone)]
+pub struct Account {
+    pub username: String,
+    /// Look-alike key of the username, see `usernames::skeleton_key`.
+    pub skeleton: String,
+    /// Argon2 hash in the PHC string format, salt included.
+    pub password_hash: String,
+    pub created_at: NaiveDateTime,
+}
+
+/// Backend that keeps user accounts and their login sessions. Sessions
+/// are looked up by a hash of their token, the token itself is not stored.
+pub trait AccountStore: Send + Sync {
+    /// Creates an account. Returns `false` if an account with the same
+    /// skeleton exists.
+    fn create_account(&self, account: &Account) -> Result<bool, StorageError>;
+
+    fn account(&self, username: &str) -> Result<Option<Account>, StorageError>;
+
+    /// Finds an account whose username looks like one with this skeleton.
+    fn account_by_skeleton(&self, skeleton: &str) -> Result<Option<Account>, StorageError>;
+
+    /// Stores a session and drops the ones that expired.
+    fn create_session(&self, token_hash: &str, username: &str, expires_at: NaiveDateTime) -> Result<(), StorageError>;
+
+    /// Returns the username of a session that is still valid at `now`.
+    fn session(&self, token_hash: &str, now: NaiveDateTime) -> Result<Option<String>, StorageError>;
+
+    fn delete_session(&self, token_hash: &str) -> Result<(), StorageError>;
+}
+
+/// A user, or an address they connected from, kept out by a moderator.
+#[derive(Clone)]
+pub struct Ban {
+    /// The banned user, or the address of a ban of an address alone.
+    pub username: String,
+    /// Look-alike key of the username; look-alikes are banned as well.
+    /// `None` for bans of an address alone.
+    pub skeleton: Option<String>,
+    /// Set for bans of an address rather than the username.
+    pub ip: Option<String>,
+    pub reason: Option<String>,
+    pub banned_by: String,
+    pub created_at: NaiveDateTime,
+    /// `None` for bans that do not expire.
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+/// Backend that keeps bans across restarts.
+pub trait BanStore: Send + Sync {
+    fn create_ban(&self, ban: &Ban) -> Result<(), StorageError>;
+
+    /// Returns a ban of the username skeleton or of the address that is
+    /// still in force at `now`.
+    fn active_ban(&self, skeleton: Option<&str>, ip: Option<&str>, now: NaiveDateTime) -> Result<Option<Ban>, StorageError>;
+
+    /// Lifts every ban of a user, those of their addresses included, but
+    /// never bans of an address alone. Returns how many there were.
+    fn delete_bans(&self, skeleton: &str) -> Result<usize, StorageError>;
+
+    /// Lifts every ban of an address, whoever it was banned with. Returns
+    /// how many there were.
+    fn delete_ip_bans(&self, ip: &str) -> Result<usize, StorageError>;
+}
+
+/// Stores of the configured backend.
+pub struct Storage {
+    pub messages: Arc<dyn MessageStore>,
+    pub accounts: Arc<dyn AccountStore>,
+    pub bans: Arc<dyn BanStore>,
+}
+
+pub fn from_config(config: &ChatConfig) -> Storage {
+    match config.storage {
+        StorageKind::Memory => {
+            log::info!("Using in-memory store");
+            let store = Arc::new(MemoryStore::new(config.memory_capacity));
+            Storage {
+                messages: store.clone(),
+                accounts: store.clone(),
+                bans: store,
+            }
+        },
+        StorageKind::Sqlite => {
+            log::info!("Using sqlite store at {}", config.database_path);
+            let store = SqliteStore::open(&config.database_path)
+                .expect("Cannot open sqlite store");
+            let store = Arc::new(store);
+            Storage {
+                messages: store.clone(),
+                accounts: store.clone(),
+                bans: store,
+            }
+        },
+    }
+}
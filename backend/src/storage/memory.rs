@@ -0,0 +1,286 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    sync::Mutex,
+};
+
+use chrono::NaiveDateTime;
+
+use super::{Account, AccountStore, Ban, BanStore, EventKind, MessageReactions, MessageStore, StorageError, StoredEvent};
+
+
+#[derive(Default)]
+struct Rooms {
+    last_id: u64,
+    events: HashMap<String, VecDeque<StoredEvent>>,
+    /// Reactions to the messages still kept, by message id.
+    reactions: HashMap<u64, MessageReactions>,
+    /// Read markers of every room, by room and username.
+    read_markers: HashMap<String, BTreeMap<String, u64>>,
+}
+
+#[derive(Default)]
+struct Accounts {
+    accounts: HashMap<String, Account>,
+    /// Username and expiry of every session, by token hash.
+    sessions: HashMap<String, (String, NaiveDateTime)>,
+}
+
+/// Keeps the last `capacity` events of every room in memory.
+/// History, reactions, read markers, accounts and bans are lost on restart.
+pub struct MemoryStore {
+    capacity: usize,
+    rooms: Mutex<Rooms>,
+    accounts: Mutex<Accounts>,
+    bans: Mutex<Vec<Ban>>,
+}
+
+impl MemoryStore {
+    pub fn new(capacity: usize) -> MemoryStore {
+        MemoryStore {
+            capacity,
+            rooms: Mutex::default(),
+            accounts: Mutex::default(),
+            bans: Mutex::default(),
+        }
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, Rooms>, StorageError> {
+        self.rooms.lock()
+            .map_err(|_| StorageError("memory store lock is poisoned".to_string()))
+    }
+
+    fn lock_accounts(&self) -> Result<std::sync::MutexGuard<'_, Accounts>, StorageError> {
+        self.accounts.lock()
+            .map_err(|_| StorageError("memory store lock is poisoned".to_string()))
+    }
+
+    fn lock_bans(&self) -> Result<std::sync::MutexGuard<'_, Vec<Ban>>, StorageError> {
+        self.bans.lock()
+            .map_err(|_| StorageError("memory store lock is poisoned".to_string()))
+    }
+}
+
+impl MessageStore for MemoryStore {
+    fn append(&self, event: &StoredEvent) -> Result<u64, StorageError> {
+        let mut rooms = self.lock()?;
+        rooms.last_id += 1;
+        let id = rooms.last_id;
+        if self.capacity == 0 {
+            return Ok(id);
+        }
+        let mut event = event.clone();
+        event.message.id = Some(id);
+        let events = rooms.events.entry(event.room.clone()).or_default();
+        let dropped = if events.len() == self.capacity { events.pop_front() } else { None };
+        events.push_back(event);
+        if let Some(dropped_id) = dropped.and_then(|event| event.message.id) {
+            rooms.reactions.remove(&dropped_id);
+        }
+        Ok(id)
+    }
+
+    fn get(&self, id: u64) -> Result<Option<StoredEvent>, StorageError> {
+        let rooms = self.lock()?;
+        let event = rooms.events.values()
+            .flat_map(|events| events.iter())
+            .find(|event| event.message.id == Some(id))
+            .cloned();
+        Ok(event)
+    }
+
+    fn update(&self, event: &StoredEvent) -> Result<(), StorageError> {
+        let mut rooms = self.lock()?;
+        let stored = rooms.events.get_mut(&event.room)
+            .and_then(|events| events.iter_mut().find(|e| e.message.id == event.message.id));
+        match stored {
+            Some(stored) => {
+                *stored = event.clone();
+                if let (true, Some(id)) = (event.message.deleted, event.message.id) {
+                    rooms.reactions.remove(&id);
+                }
+                Ok(())
+            },
+            None => Err(StorageError(format!("event {:?} is not in memory", event.message.id))),
+        }
+    }
+
+    fn replies(&self, message_id: u64) -> Result<Vec<StoredEvent>, StorageError> {
+        let rooms = self.lock()?;
+        let mut events: Vec<StoredEvent> = rooms.events.values()
+            .flat_map(|events| events.iter())
+            .filter(|event| event.message.reply_to == Some(message_id))
+            .cloned()
+            .collect();
+        events.sort_by_key(|event| event.message.id);
+        Ok(events)
+    }
+
+    fn recent(&self, room: &str, limit: usize) -> Result<Vec<StoredEvent>, StorageError> {
+        let rooms = self.lock()?;
+        let events = match rooms.events.get(room) {
+            Some(events) => events,
+            _ => return Ok(Vec::new()),
+        };
+        let mut messages: Vec<StoredEvent> = events.iter().rev()
+            .filter(|event| event.kind == EventKind::Message)
+            .take(limit)
+            .cloned()
+            .collect();
+        messages.reverse();
+        Ok(messages)
+    }
+
+    fn add_reaction(&self, message_id: u64, emoji: &str, username: &str) -> Result<bool, StorageError> {
+        let mut rooms = self.lock()?;
+        let users = rooms.reactions.entry(message_id).or_default().entry(emoji.to_string()).or_default();
+        Ok(users.insert(username.to_string()))
+    }
+
+    fn remove_reaction(&self, message_id: u64, emoji: &str, username: &str) -> Result<bool, StorageError> {
+        let mut rooms = self.lock()?;
+        let msg_reactions = match rooms.reactions.get_mut(&message_id) {
+            Some(msg_reactions) => msg_reactions,
+            None => return Ok(false),
+        };
+        let removed = msg_reactions.get_mut(emoji)
+            .map(|users| users.remove(username))
+            .unwrap_or(false);
+        msg_reactions.retain(|_, users| !users.is_empty());
+        if msg_reactions.is_empty() {
+            rooms.reactions.remove(&message_id);
+        }
+        Ok(removed)
+    }
+
+    fn reactions(&self, message_ids: &[u64]) -> Result<HashMap<u64, MessageReactions>, StorageError> {
+        let rooms = self.lock()?;
+        let reactions = message_ids.iter()
+            .filter_map(|id| rooms.reactions.get(id).map(|msg_reactions| (*id, msg_reactions.clone())))
+            .collect();
+        Ok(reactions)
+    }
+
+    fn mark_read(&self, room: &str, username: &str, message_id: u64) -> Result<Option<u64>, StorageError> {
+        let mut rooms = self.lock()?;
+        let marker = rooms.read_markers.entry(room.to_string()).or_default()
+            .entry(username.to_string()).or_default();
+        if *marker >= message_id {
+            return Ok(None);
+        }
+        Ok(Some(std::mem::replace(marker, message_id)))
+    }
+
+    fn authors(&self, room: &str, after: u64, up_to: u64) -> Result<BTreeSet<String>, StorageError> {
+        let rooms = self.lock()?;
+        let authors = rooms.events.get(room).into_iter().flatten()
+            .filter(|event| event.kind == EventKind::Message)
+            .filter(|event| event.message.id.is_some_and(|id| id > after && id <= up_to))
+            .map(|event| event.message.author.clone())
+            .collect();
+        Ok(authors)
+    }
+
+    fn read_markers(&self, room: &str) -> Result<BTreeMap<String, u64>, StorageError> {
+        Ok(self.lock()?.read_markers.get(room).cloned().unwrap_or_default())
+    }
+
+    fn rename_user(&self, room: &str, old_username: &str, new_username: &str) -> Result<(), StorageError> {
+        let mut rooms = self.lock()?;
+        let Rooms { events, reactions, read_markers, .. } = &mut *rooms;
+        let message_ids = events.get(room).into_iter().flatten().filter_map(|event| event.message.id);
+        for message_id in message_ids {
+            for users in reactions.get_mut(&message_id).into_iter().flat_map(|r| r.values_mut()) {
+                if users.remove(old_username) {
+                    users.insert(new_username.to_string());
+                }
+            }
+        }
+        if let Some(markers) = read_markers.get_mut(room) {
+            if let Some(old_marker) = markers.remove(old_username) {
+                let marker = markers.entry(new_username.to_string()).or_default();
+                *marker = old_marker.max(*marker);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AccountStore for MemoryStore {
+    fn create_account(&self, account: &Account) -> Result<bool, StorageError> {
+        let mut accounts = self.lock_accounts()?;
+        if accounts.accounts.values().any(|a| a.skeleton == account.skeleton) {
+            return Ok(false);
+        }
+        accounts.accounts.insert(account.username.clone(), account.clone());
+        Ok(true)
+    }
+
+    fn account(&self, username: &str) -> Result<Option<Account>, StorageError> {
+        Ok(self.lock_accounts()?.accounts.get(username).cloned())
+    }
+
+    fn account_by_skeleton(&self, skeleton: &str) -> Result<Option<Account>, StorageError> {
+        let accounts = self.lock_accounts()?;
+        let account = accounts.accounts.values().find(|a| a.skeleton == skeleton).cloned();
+        Ok(account)
+    }
+
+    fn create_session(&self, token_hash: &str, username: &str, expires_at: NaiveDateTime) -> Result<(), StorageError> {
+        let mut accounts = self.lock_accounts()?;
+        let now = chrono::Utc::now().naive_utc();
+        accounts.sessions.retain(|_, (_, expires_at)| *expires_at > now);
+        accounts.sessions.insert(token_hash.to_string(), (username.to_string(), expires_at));
+        Ok(())
+    }
+
+    fn session(&self, token_hash: &str, now: NaiveDateTime) -> Result<Option<String>, StorageError> {
+        let accounts = self.lock_accounts()?;
+        let username = accounts.sessions.get(token_hash)
+            .filter(|(_, expires_at)| *expires_at > now)
+            .map(|(username, _)| username.clone());
+        Ok(username)
+    }
+
+    fn delete_session(&self, token_hash: &str) -> Result<(), StorageError> {
+        self.lock_accounts()?.sessions.remove(token_hash);
+        Ok(())
+    }
+}
+
+impl BanStore for MemoryStore {
+    fn create_ban(&self, ban: &Ban) -> Result<(), StorageError> {
+        let mut bans = self.lock_bans()?;
+        let now = chrono::Utc::now().naive_utc();
+        bans.retain(|ban| ban.expires_at.is_none_or(|expires_at| expires_at > now));
+        bans.push(ban.clone());
+        Ok(())
+    }
+
+    fn active_ban(&self, skeleton: Option<&str>, ip: Option<&str>, now: NaiveDateTime) -> Result<Option<Ban>, StorageError> {
+        let bans = self.lock_bans()?;
+        let ban = bans.iter()
+            .filter(|ban| ban.expires_at.is_none_or(|expires_at| expires_at > now))
+            .filter(|ban| match &ban.ip {
+                Some(banned_ip) => Some(banned_ip.as_str()) == ip,
+                None => ban.skeleton.is_some() && ban.skeleton.as_deref() == skeleton,
+            })
+            // The ban lasting longest, permanent ones first.
+            .max_by_key(|ban| ban.expires_at.map_or((1, None), |expires_at| (0, Some(expires_at))))
+            .cloned();
+        Ok(ban)
+    }
+
+    fn delete_bans(&self, skeleton: &str) -> Result<usize, StorageError> {
+        let mut bans = self.lock_bans()?;
+        let before = bans.len();
+        bans.retain(|ban| skeleton.is_empty() || ban.skeleton.as_deref() != Some(skeleton));
+        Ok(before - bans.len())
+    }
+
+    fn delete_ip_bans(&self, ip: &str) -> Result<usize, StorageError> {
+        let mut bans = self.lock_bans()?;
+        let before = bans.len();
+        bans.retain(|ban| ban.ip.as_deref() != Some(ip));
+        Ok(before - bans.len())
+    }
+}
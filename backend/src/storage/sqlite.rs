@@ -0,0 +1,456 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::Mutex,
+};
+
+use chrono::NaiveDateTime;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Row};
+
+use common::ChatMessage;
+
+use crate::usernames;
+
+use super::{Account, AccountStore, Ban, BanStore, EventKind, MessageReactions, MessageStore, StorageError, StoredEvent};
+
+/// Schema changes, applied in order. The number of applied ones is kept
+/// in the database's `user_version`.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS events (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        room TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        author TEXT NOT NULL,
+        message TEXT NOT NULL,
+        created_at TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS events_room_id ON events (room, id);",
+    "ALTER TABLE events ADD COLUMN edited_at TEXT;
+    ALTER TABLE events ADD COLUMN deleted INTEGER NOT NULL DEFAULT 0;",
+    "ALTER TABLE events ADD COLUMN reply_to INTEGER;
+    CREATE INDEX IF NOT EXISTS events_reply_to ON events (reply_to);",
+    "CREATE TABLE IF NOT EXISTS accounts (
+        username TEXT PRIMARY KEY,
+        password_hash TEXT NOT NULL,
+        created_at TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS sessions (
+        token_hash TEXT PRIMARY KEY,
+        username TEXT NOT NULL REFERENCES accounts (username),
+        expires_at TEXT NOT NULL
+    );",
+    "ALTER TABLE accounts ADD COLUMN skeleton TEXT;
+    CREATE INDEX IF NOT EXISTS accounts_skeleton ON accounts (skeleton);",
+    "CREATE TABLE IF NOT EXISTS bans (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        username TEXT NOT NULL,
+        skeleton TEXT NOT NULL,
+        ip TEXT,
+        reason TEXT,
+        banned_by TEXT NOT NULL,
+        created_at TEXT NOT NULL,
+        expires_at TEXT
+    );
+    CREATE INDEX IF NOT EXISTS bans_skeleton ON bans (skeleton);
+    CREATE INDEX IF NOT EXISTS bans_ip ON bans (ip);",
+    "ALTER TABLE events ADD COLUMN owner TEXT;",
+    "CREATE TABLE IF NOT EXISTS reactions (
+        message_id INTEGER NOT NULL REFERENCES events (id),
+        emoji TEXT NOT NULL,
+        username TEXT NOT NULL,
+        PRIMARY KEY (message_id, emoji, username)
+    );
+    CREATE TABLE IF NOT EXISTS read_markers (
+        room TEXT NOT NULL,
+        username TEXT NOT NULL,
+        message_id INTEGER NOT NULL,
+        PRIMARY KEY (room, username)
+    );",
+    // Bans of an address alone have no username skeleton.
+    "CREATE TABLE bans_new (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        username TEXT NOT NULL,
+        skeleton TEXT,
+        ip TEXT,
+        reason TEXT,
+        banned_by TEXT NOT NULL,
+        created_at TEXT NOT NULL,
+        expires_at TEXT
+    );
+    INSERT INTO bans_new (id, username, skeleton, ip, reason, banned_by, created_at, expires_at)
+        SELECT id, username, NULLIF(skeleton, ''), ip, reason, banned_by, created_at, expires_at FROM bans;
+    DROP TABLE bans;
+    ALTER TABLE bans_new RENAME TO bans;
+    CREATE INDEX IF NOT EXISTS bans_skeleton ON bans (skeleton);
+    CREATE INDEX IF NOT EXISTS bans_ip ON bans (ip);",
+];
+
+const EVENT_COLUMNS: &str = "id, room, kind, author, message, created_at, edited_at, deleted, reply_to, owner";
+const ACCOUNT_COLUMNS: &str = "username, skeleton, password_hash, created_at";
+const BAN_COLUMNS: &str = "username, skeleton, ip, reason, banned_by, created_at, expires_at";
+
+
+/// Persists every event in an embedded SQLite database.
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    pub fn open(path: &str) -> Result<SqliteStore, StorageError> {
+        let conn = Connection::open(path)?;
+        migrate(&conn)?;
+        fill_skeletons(&conn)?;
+        Ok(SqliteStore {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, Connection>, StorageError> {
+        self.conn.lock()
+            .map_err(|_| StorageError("sqlite connection lock is poisoned".to_string()))
+    }
+}
+
+fn migrate(conn: &Connection) -> Result<(), StorageError> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (idx, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        log::info!("Applying sqlite migration {}", idx + 1);
+        conn.execute_batch(migration)?;
+        conn.pragma_update(None, "user_version", idx + 1)?;
+    }
+    Ok(())
+}
+
+/// Computes skeletons of accounts created before they were stored.
+fn fill_skeletons(conn: &Connection) -> Result<(), StorageError> {
+    let mut stmt = conn.prepare("SELECT username FROM accounts WHERE skeleton IS NULL")?;
+    let usernames = stmt.query_map([], |row| row.get(0))?
+        .collect::<Result<Vec<String>, rusqlite::Error>>()?;
+    for username in usernames {
+        conn.execute(
+            "UPDATE accounts SET skeleton = ?1 WHERE username = ?2",
+            params![usernames::skeleton_key(&username), username],
+        )?;
+    }
+    Ok(())
+}
+
+fn account_from_row(row: &Row) -> Result<Account, rusqlite::Error> {
+    Ok(Account {
+        username: row.get("username")?,
+        skeleton: row.get("skeleton")?,
+        password_hash: row.get("password_hash")?,
+        created_at: row.get("created_at")?,
+    })
+}
+
+fn ban_from_row(row: &Row) -> Result<Ban, rusqlite::Error> {
+    Ok(Ban {
+        username: row.get("username")?,
+        skeleton: row.get("skeleton")?,
+        ip: row.get("ip")?,
+        reason: row.get("reason")?,
+        banned_by: row.get("banned_by")?,
+        created_at: row.get("created_at")?,
+        expires_at: row.get("expires_at")?,
+    })
+}
+
+fn event_from_row(row: &Row) -> Result<StoredEvent, rusqlite::Error> {
+    let kind: String = row.get("kind")?;
+    Ok(StoredEvent {
+        room: row.get("room")?,
+        kind: EventKind::parse(&kind).unwrap_or(EventKind::Message),
+        message: ChatMessage {
+            id: row.get("id")?,
+            author: row.get("author")?,
+            message: row.get("message")?,
+            created_at: row.get("created_at")?,
+            edited_at: row.get("edited_at")?,
+            deleted: row.get("deleted")?,
+            reactions: Vec::new(),
+            reply_to: row.get("reply_to")?,
+        },
+        owner: row.get("owner")?,
+    })
+}
+
+impl MessageStore for SqliteStore {
+    fn append(&self, event: &StoredEvent) -> Result<u64, StorageError> {
+        let conn = self.lock()?;
+        conn.execute(
+            "INSERT INTO events (room, kind, author, message, created_at, edited_at, deleted, reply_to, owner)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                event.room,
+                event.kind.as_str(),
+                event.message.author,
+                event.message.message,
+                event.message.created_at,
+                event.message.edited_at,
+                event.message.deleted,
+                event.message.reply_to,
+                event.owner,
+            ],
+        )?;
+        Ok(conn.last_insert_rowid() as u64)
+    }
+
+    fn get(&self, id: u64) -> Result<Option<StoredEvent>, StorageError> {
+        let conn = self.lock()?;
+        let event = conn.query_row(
+            &format!("SELECT {} FROM events WHERE id = ?1", EVENT_COLUMNS),
+            params![id],
+            event_from_row,
+        ).optional()?;
+        Ok(event)
+    }
+
+    fn update(&self, event: &StoredEvent) -> Result<(), StorageError> {
+        let id = event.message.id
+            .ok_or_else(|| StorageError("cannot update an event without id".to_string()))?;
+        let conn = self.lock()?;
+        let tx = conn.unchecked_transaction()?;
+        tx.execute(
+            "UPDATE events SET message = ?1, edited_at = ?2, deleted = ?3 WHERE id = ?4",
+            params![event.message.message, event.message.edited_at, event.message.deleted, id],
+        )?;
+        if event.message.deleted {
+            tx.execute("DELETE FROM reactions WHERE message_id = ?1", params![id])?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn replies(&self, message_id: u64) -> Result<Vec<StoredEvent>, StorageError> {
+        let conn = self.lock()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM events WHERE reply_to = ?1 ORDER BY id ASC",
+            EVENT_COLUMNS,
+        ))?;
+        let rows = stmt.query_map(params![message_id], event_from_row)?;
+        let events = rows.collect::<Result<Vec<StoredEvent>, rusqlite::Error>>()?;
+        Ok(events)
+    }
+
+    fn recent(&self, room: &str, limit: usize) -> Result<Vec<StoredEvent>, StorageError> {
+        let conn = self.lock()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {0} FROM (
+                SELECT {0} FROM events
+                WHERE room = ?1 AND kind = ?2 ORDER BY id DESC LIMIT ?3
+            ) ORDER BY id ASC",
+            EVENT_COLUMNS,
+        ))?;
+        let rows = stmt.query_map(params![room, EventKind::Message.as_str(), limit as i64], event_from_row)?;
+        let events = rows.collect::<Result<Vec<StoredEvent>, rusqlite::Error>>()?;
+        Ok(events)
+    }
+
+    fn add_reaction(&self, message_id: u64, emoji: &str, username: &str) -> Result<bool, StorageError> {
+        let conn = self.lock()?;
+        let inserted = conn.execute(
+            "INSERT OR IGNORE INTO reactions (message_id, emoji, username) VALUES (?1, ?2, ?3)",
+            params![message_id, emoji, username],
+        )?;
+        Ok(inserted == 1)
+    }
+
+    fn remove_reaction(&self, message_id: u64, emoji: &str, username: &str) -> Result<bool, StorageError> {
+        let conn = self.lock()?;
+        let deleted = conn.execute(
+            "DELETE FROM reactions WHERE message_id = ?1 AND emoji = ?2 AND username = ?3",
+            params![message_id, emoji, username],
+        )?;
+        Ok(deleted == 1)
+    }
+
+    fn reactions(&self, message_ids: &[u64]) -> Result<HashMap<u64, MessageReactions>, StorageError> {
+        let mut reactions: HashMap<u64, MessageReactions> = HashMap::new();
+        if message_ids.is_empty() {
+            return Ok(reactions);
+        }
+        let conn = self.lock()?;
+        let placeholders = vec!["?"; message_ids.len()].join(", ");
+        let mut stmt = conn.prepare(&format!(
+            "SELECT message_id, emoji, username FROM reactions WHERE message_id IN ({})",
+            placeholders,
+        ))?;
+        let rows = stmt.query_map(params_from_iter(message_ids), |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        for row in rows {
+            let (message_id, emoji, username): (u64, String, String) = row?;
+            reactions.entry(message_id).or_default().entry(emoji).or_default().insert(username);
+        }
+        Ok(reactions)
+    }
+
+    fn mark_read(&self, room: &str, username: &str, message_id: u64) -> Result<Option<u64>, StorageError> {
+        let conn = self.lock()?;
+        let tx = conn.unchecked_transaction()?;
+        let previous: u64 = tx.query_row(
+            "SELECT message_id FROM read_markers WHERE room = ?1 AND username = ?2",
+            params![room, username],
+            |row| row.get(0),
+        ).optional()?.unwrap_or(0);
+        if previous >= message_id {
+            return Ok(None);
+        }
+        tx.execute(
+            "INSERT INTO read_markers (room, username, message_id) VALUES (?1, ?2, ?3)
+            ON CONFLICT (room, username) DO UPDATE SET message_id = excluded.message_id",
+            params![room, username, message_id],
+        )?;
+        tx.commit()?;
+        Ok(Some(previous))
+    }
+
+    fn authors(&self, room: &str, after: u64, up_to: u64) -> Result<BTreeSet<String>, StorageError> {
+        let conn = self.lock()?;
+        let mut stmt = conn.prepare(
+            "SELECT DISTINCT author FROM events WHERE room = ?1 AND kind = ?2 AND id > ?3 AND id <= ?4",
+        )?;
+        let rows = stmt.query_map(params![room, EventKind::Message.as_str(), after, up_to], |row| row.get(0))?;
+        let authors = rows.collect::<Result<BTreeSet<String>, rusqlite::Error>>()?;
+        Ok(authors)
+    }
+
+    fn read_markers(&self, room: &str) -> Result<BTreeMap<String, u64>, StorageError> {
+        let conn = self.lock()?;
+        let mut stmt = conn.prepare("SELECT username, message_id FROM read_markers WHERE room = ?1")?;
+        let rows = stmt.query_map(params![room], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        let markers = rows.collect::<Result<BTreeMap<String, u64>, rusqlite::Error>>()?;
+        Ok(markers)
+    }
+
+    fn rename_user(&self, room: &str, old_username: &str, new_username: &str) -> Result<(), StorageError> {
+        let conn = self.lock()?;
+        let tx = conn.unchecked_transaction()?;
+        // Reactions the new name made already are kept, the old ones dropped.
+        tx.execute(
+            "UPDATE OR IGNORE reactions SET username = ?3
+            WHERE username = ?2 AND message_id IN (SELECT id FROM events WHERE room = ?1)",
+            params![room, old_username, new_username],
+        )?;
+        tx.execute(
+            "DELETE FROM reactions
+            WHERE username = ?2 AND message_id IN (SELECT id FROM events WHERE room = ?1)",
+            params![room, old_username],
+        )?;
+        tx.execute(
+            "INSERT INTO read_markers (room, username, message_id)
+            SELECT room, ?3, message_id FROM read_markers WHERE room = ?1 AND username = ?2
+            ON CONFLICT (room, username) DO UPDATE SET message_id = MAX(message_id, excluded.message_id)",
+            params![room, old_username, new_username],
+        )?;
+        tx.execute("DELETE FROM read_markers WHERE room = ?1 AND username = ?2", params![room, old_username])?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+impl AccountStore for SqliteStore {
+    fn create_account(&self, account: &Account) -> Result<bool, StorageError> {
+        // The connection lock makes the check and the insert atomic.
+        let conn = self.lock()?;
+        let exists: bool = conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM accounts WHERE skeleton = ?1)",
+            params![account.skeleton],
+            |row| row.get(0),
+        )?;
+        if exists {
+            return Ok(false);
+        }
+        let inserted = conn.execute(
+            "INSERT OR IGNORE INTO accounts (username, skeleton, password_hash, created_at) VALUES (?1, ?2, ?3, ?4)",
+            params![account.username, account.skeleton, account.password_hash, account.created_at],
+        )?;
+        Ok(inserted == 1)
+    }
+
+    fn account(&self, username: &str) -> Result<Option<Account>, StorageError> {
+        let conn = self.lock()?;
+        let account = conn.query_row(
+            &format!("SELECT {} FROM accounts WHERE username = ?1", ACCOUNT_COLUMNS),
+            params![username],
+            account_from_row,
+        ).optional()?;
+        Ok(account)
+    }
+
+    fn account_by_skeleton(&self, skeleton: &str) -> Result<Option<Account>, StorageError> {
+        let conn = self.lock()?;
+        let account = conn.query_row(
+            &format!("SELECT {} FROM accounts WHERE skeleton = ?1 LIMIT 1", ACCOUNT_COLUMNS),
+            params![skeleton],
+            account_from_row,
+        ).optional()?;
+        Ok(account)
+    }
+
+    fn create_session(&self, token_hash: &str, username: &str, expires_at: NaiveDateTime) -> Result<(), StorageError> {
+        let conn = self.lock()?;
+        let now = chrono::Utc::now().naive_utc();
+        conn.execute("DELETE FROM sessions WHERE expires_at <= ?1", params![now])?;
+        conn.execute(
+            "INSERT INTO sessions (token_hash, username, expires_at) VALUES (?1, ?2, ?3)",
+            params![token_hash, username, expires_at],
+        )?;
+        Ok(())
+    }
+
+    fn session(&self, token_hash: &str, now: NaiveDateTime) -> Result<Option<String>, StorageError> {
+        let conn = self.lock()?;
+        let username = conn.query_row(
+            "SELECT username FROM sessions WHERE token_hash = ?1 AND expires_at > ?2",
+            params![token_hash, now],
+            |row| row.get(0),
+        ).optional()?;
+        Ok(username)
+    }
+
+    fn delete_session(&self, token_hash: &str) -> Result<(), StorageError> {
+        let conn = self.lock()?;
+        conn.execute("DELETE FROM sessions WHERE token_hash = ?1", params![token_hash])?;
+        Ok(())
+    }
+}
+
+impl BanStore for SqliteStore {
+    fn create_ban(&self, ban: &Ban) -> Result<(), StorageError> {
+        let conn = self.lock()?;
+        let now = chrono::Utc::now().naive_utc();
+        conn.execute("DELETE FROM bans WHERE expires_at <= ?1", params![now])?;
+        conn.execute(
+            "INSERT INTO bans (username, skeleton, ip, reason, banned_by, created_at, expires_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![ban.username, ban.skeleton, ban.ip, ban.reason, ban.banned_by, ban.created_at, ban.expires_at],
+        )?;
+        Ok(())
+    }
+
+    fn active_ban(&self, skeleton: Option<&str>, ip: Option<&str>, now: NaiveDateTime) -> Result<Option<Ban>, StorageError> {
+        let conn = self.lock()?;
+        let ban = conn.query_row(
+            &format!(
+                "SELECT {} FROM bans
+                WHERE ((ip IS NULL AND skeleton = ?1) OR ip = ?2) AND (expires_at IS NULL OR expires_at > ?3)
+                ORDER BY expires_at IS NULL DESC, expires_at DESC LIMIT 1",
+                BAN_COLUMNS,
+            ),
+            params![skeleton, ip, now],
+            ban_from_row,
+        ).optional()?;
+        Ok(ban)
+    }
+
+    fn delete_bans(&self, skeleton: &str) -> Result<usize, StorageError> {
+        let conn = self.lock()?;
+        let deleted = conn.execute("DELETE FROM bans WHERE skeleton = ?1 AND skeleton <> ''", params![skeleton])?;
+        Ok(deleted)
+    }
+
+    fn delete_ip_bans(&self, ip: &str) -> Result<usize, StorageError> {
+        let conn = self.lock()?;
+        let deleted = conn.execute("DELETE FROM bans WHERE ip = ?1", params![ip])?;
+        Ok(deleted)
+    }
+}
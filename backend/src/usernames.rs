@@ -0,0 +1,118 @@
+use std::fmt;
+
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::{skeleton, MixedScript};
+
+pub const MAX_USERNAME_LEN: usize = 32;
+/// Compared by skeleton, so look-alikes such as `Sуstem` are reserved too.
+const RESERVED_USERNAMES: &[&str] = &["system", "admin", "administrator", "moderator", "server", "root"];
+
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum UsernameError {
+    Empty,
+    TooLong,
+    InvalidCharacters,
+    MixedScripts,
+    Reserved,
+    Taken,
+    Banned,
+}
+
+impl fmt::Display for UsernameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UsernameError::Empty => write!(f, "username is empty"),
+            UsernameError::TooLong => write!(f, "username is longer than {} characters", MAX_USERNAME_LEN),
+            UsernameError::InvalidCharacters => write!(
+                f, "username may only contain letters, digits, single spaces and `_`, `-`, `.`",
+            ),
+            UsernameError::MixedScripts => write!(f, "username mixes letters of different scripts"),
+            UsernameError::Reserved => write!(f, "username is reserved"),
+            UsernameError::Taken => write!(f, "username is taken"),
+            UsernameError::Banned => write!(f, "username is banned"),
+        }
+    }
+}
+
+fn is_allowed_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, ' ' | '_' | '-' | '.')
+}
+
+/// Normalizes a requested username to NFC and checks it against the
+/// naming rules. Returns the name to use.
+pub fn normalize(username: &str) -> Result<String, UsernameError> {
+    let username: String = username.trim().nfc().collect();
+    if username.is_empty() {
+        return Err(UsernameError::Empty);
+    }
+    if username.chars().count() > MAX_USERNAME_LEN {
+        return Err(UsernameError::TooLong);
+    }
+    if !username.chars().all(is_allowed_char) || username.contains("  ") {
+        return Err(UsernameError::InvalidCharacters);
+    }
+    if !username.as_str().is_single_script() {
+        return Err(UsernameError::MixedScripts);
+    }
+    let key = skeleton_key(&username);
+    if RESERVED_USERNAMES.iter().any(|reserved| skeleton_key(reserved) == key) {
+        return Err(UsernameError::Reserved);
+    }
+    Ok(username)
+}
+
+/// Key under which usernames that look alike collide: the UTS #39
+/// skeleton of the lowercase name. Lowercasing turns `I` into `i`, yet
+/// `I` looks like `l`, so `i` is folded into `l` as well.
+pub fn skeleton_key(username: &str) -> String {
+    skeleton(&username.to_lowercase())
+        .map(|c| if c == 'i' { 'l' } else { c })
+        .collect()
+}
+
+/// Whether two usernames would be mistaken for each other.
+pub fn is_confusable(first: &str, second: &str) -> bool {
+    skeleton_key(first) == skeleton_key(second)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_to_nfc() {
+        assert_eq!(normalize("  Cafe\u{301} ").unwrap(), "Caf\u{e9}");
+        assert!(is_confusable("Cafe\u{301}", "Caf\u{e9}"));
+    }
+
+    #[test]
+    fn folds_look_alikes() {
+        assert!(is_confusable("Alice", "AIice"));
+        assert!(is_confusable("alice", "ALICE"));
+        assert!(is_confusable("paypal", "paypa1"));
+        assert!(!is_confusable("alice", "alicia"));
+    }
+
+    #[test]
+    fn reserves_names_and_their_look_alikes() {
+        for name in ["system", "SYSTEM", "Admin", "adm1n", "ADMlN", "moderator", "root"] {
+            assert_eq!(normalize(name), Err(UsernameError::Reserved), "{}", name);
+        }
+        assert!(normalize("rooted").is_ok());
+    }
+
+    #[test]
+    fn rejects_bad_names() {
+        assert_eq!(normalize(""), Err(UsernameError::Empty));
+        assert_eq!(normalize("   "), Err(UsernameError::Empty));
+        assert!(normalize(&"a".repeat(MAX_USERNAME_LEN)).is_ok());
+        assert_eq!(normalize(&"a".repeat(MAX_USERNAME_LEN + 1)), Err(UsernameError::TooLong));
+        assert_eq!(normalize("bob\u{7}"), Err(UsernameError::InvalidCharacters));
+        assert_eq!(normalize("bob\u{202e}"), Err(UsernameError::InvalidCharacters));
+        assert_eq!(normalize("bob\tsmith"), Err(UsernameError::InvalidCharacters));
+        assert_eq!(normalize("bob  smith"), Err(UsernameError::InvalidCharacters));
+        assert_eq!(normalize("b\u{43e}b"), Err(UsernameError::MixedScripts));
+        assert_eq!(normalize("bob smith_2.0-x").unwrap(), "bob smith_2.0-x");
+    }
+}
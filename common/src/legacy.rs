@@ -0,0 +1,540 @@
+//! Flat message format of protocol versions 1 and 2, where the payload
+//! fields that are set depend on `message_type`.
+
+use std::{collections::BTreeMap, fmt};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    ChatError, ChatMessage, ClientMessage, ErrorCode, Reaction, Request, ServerMessage,
+    WebSocketMessageType,
+};
+
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WebSocketMessage {
+    pub message_type: WebSocketMessageType,
+    pub message: Option<ChatMessage>,
+    pub users: Option<Vec<String>>,
+    pub username: Option<String>,
+    pub room: Option<String>,
+    pub rooms: Option<Vec<String>>,
+    pub history: Option<Vec<ChatMessage>>,
+    pub recipient: Option<String>,
+    pub message_id: Option<u64>,
+    pub emoji: Option<String>,
+    pub reactions: Option<Vec<Reaction>>,
+    pub typing: Option<bool>,
+    /// Number of other users a message was delivered to.
+    pub delivered: Option<usize>,
+    /// Set by clients on requests and echoed in errors about them.
+    pub request_id: Option<String>,
+    pub error: Option<ChatError>,
+    /// Protocol version of a `Hello` or the negotiated one of a `Welcome`.
+    pub version: Option<u32>,
+    pub capabilities: Option<Vec<String>>,
+}
+
+impl WebSocketMessage {
+    fn new(message_type: WebSocketMessageType) -> WebSocketMessage {
+        WebSocketMessage {
+            message_type,
+            message: None,
+            users: None,
+            username: None,
+            room: None,
+            rooms: None,
+            history: None,
+            recipient: None,
+            message_id: None,
+            emoji: None,
+            reactions: None,
+            typing: None,
+            delivered: None,
+            request_id: None,
+            error: None,
+            version: None,
+            capabilities: None,
+        }
+    }
+}
+
+impl fmt::Display for WebSocketMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", json!(self))
+    }
+}
+
+fn missing(field: &str) -> ChatError {
+    ChatError::new(ErrorCode::InvalidRequest, format!("Request needs {}", field))
+}
+
+fn malformed(field: &str) -> ChatError {
+    ChatError::new(ErrorCode::InvalidMessage, format!("Message has no {}", field))
+}
+
+impl TryFrom<WebSocketMessage> for Request {
+    type Error = ChatError;
+
+    fn try_from(msg: WebSocketMessage) -> Result<Request, ChatError> {
+        let message_type = msg.message_type;
+        let room = msg.room;
+        let message = match message_type {
+            WebSocketMessageType::Hello => ClientMessage::Hello {
+                version: msg.version.ok_or_else(|| missing("version"))?,
+                capabilities: msg.capabilities.unwrap_or_default(),
+            },
+            WebSocketMessageType::NewMessage => {
+                let chat_msg = msg.message.ok_or_else(|| missing("message"))?;
+                ClientMessage::NewMessage {
+                    room,
+                    text: chat_msg.message,
+                    reply_to: chat_msg.reply_to,
+                }
+            },
+            WebSocketMessageType::DirectMessage => match (msg.message, msg.recipient) {
+                (Some(chat_msg), Some(recipient)) => ClientMessage::DirectMessage {
+                    recipient,
+                    text: chat_msg.message,
+                },
+                _ => return Err(missing("message and recipient")),
+            },
+            WebSocketMessageType::EditMessage => match (msg.message_id, msg.message) {
+                (Some(message_id), Some(chat_msg)) => ClientMessage::EditMessage {
+                    room,
+                    message_id,
+                    text: chat_msg.message,
+                },
+                _ => return Err(missing("message_id and message")),
+            },
+            WebSocketMessageType::DeleteMessage => ClientMessage::DeleteMessage {
+                room,
+                message_id: msg.message_id.ok_or_else(|| missing("message_id"))?,
+            },
+            WebSocketMessageType::AddReaction | WebSocketMessageType::RemoveReaction => {
+                let (message_id, emoji) = match (msg.message_id, msg.emoji) {
+                    (Some(message_id), Some(emoji)) => (message_id, emoji),
+                    _ => return Err(missing("message_id and emoji")),
+                };
+                if message_type == WebSocketMessageType::AddReaction {
+                    ClientMessage::AddReaction { room, message_id, emoji }
+                } else {
+                    ClientMessage::RemoveReaction { room, message_id, emoji }
+                }
+            },
+            WebSocketMessageType::Thread => ClientMessage::Thread {
+                room,
+                message_id: msg.message_id.ok_or_else(|| missing("message_id"))?,
+            },
+            WebSocketMessageType::Typing => ClientMessage::Typing {
+                room,
+                typing: msg.typing.unwrap_or(false),
+            },
+            WebSocketMessageType::Read => ClientMessage::Read {
+                room,
+                message_id: msg.message_id.ok_or_else(|| missing("message_id"))?,
+            },
+            WebSocketMessageType::UsernameChange => ClientMessage::UsernameChange {
+                username: msg.username.ok_or_else(|| missing("username"))?,
+            },
+            WebSocketMessageType::UserList => ClientMessage::UserList { room },
+            WebSocketMessageType::JoinRoom => ClientMessage::JoinRoom {
+                room: room.ok_or_else(|| missing("room"))?,
+            },
+            WebSocketMessageType::LeaveRoom => ClientMessage::LeaveRoom {
+                room: room.ok_or_else(|| missing("room"))?,
+            },
+            WebSocketMessageType::RoomList => ClientMessage::RoomList,
+            // Presence and moderation came after this format was frozen.
+            WebSocketMessageType::Presence
+            | WebSocketMessageType::Kick
+            | WebSocketMessageType::Ban
+            | WebSocketMessageType::Unban
+            | WebSocketMessageType::Mute
+            | WebSocketMessageType::BanIp
+            | WebSocketMessageType::UnbanIp => {
+                return Err(ChatError::new(ErrorCode::Unsupported, "This message type needs protocol version 3"));
+            },
+            WebSocketMessageType::System
+            | WebSocketMessageType::History
+            | WebSocketMessageType::Ack
+            | WebSocketMessageType::Error
+            | WebSocketMessageType::Welcome => {
+                return Err(ChatError::new(ErrorCode::Unsupported, "Clients cannot send this message type"));
+            },
+        };
+        Ok(Request {
+            request_id: msg.request_id,
+            message,
+        })
+    }
+}
+
+impl TryFrom<WebSocketMessage> for ServerMessage {
+    type Error = ChatError;
+
+    fn try_from(msg: WebSocketMessage) -> Result<ServerMessage, ChatError> {
+        let message_type = msg.message_type;
+        let room = msg.room.ok_or_else(|| malformed("room"));
+        let message = msg.message.ok_or_else(|| malformed("message"));
+        let message_id = msg.message_id.ok_or_else(|| malformed("message_id"));
+        let username = msg.username.ok_or_else(|| malformed("username"));
+        let server_msg = match message_type {
+            WebSocketMessageType::Welcome => ServerMessage::Welcome {
+                version: msg.version.ok_or_else(|| malformed("version"))?,
+                capabilities: msg.capabilities.unwrap_or_default(),
+            },
+            WebSocketMessageType::NewMessage => ServerMessage::NewMessage { room: room?, message: message? },
+            WebSocketMessageType::System => ServerMessage::System { room: room.ok(), message: message? },
+            WebSocketMessageType::DirectMessage => ServerMessage::DirectMessage {
+                recipient: msg.recipient.ok_or_else(|| malformed("recipient"))?,
+                message: message?,
+            },
+            WebSocketMessageType::EditMessage => ServerMessage::EditMessage { room: room?, message: message? },
+            WebSocketMessageType::DeleteMessage => ServerMessage::DeleteMessage { room: room?, message: message? },
+            WebSocketMessageType::AddReaction | WebSocketMessageType::RemoveReaction => {
+                let (room, message_id, username) = (room?, message_id?, username?);
+                let emoji = msg.emoji.ok_or_else(|| malformed("emoji"))?;
+                let reactions = msg.reactions.ok_or_else(|| malformed("reactions"))?;
+                if message_type == WebSocketMessageType::AddReaction {
+                    ServerMessage::AddReaction { room, message_id, emoji, username, reactions }
+                } else {
+                    ServerMessage::RemoveReaction { room, message_id, emoji, username, reactions }
+                }
+            },
+            WebSocketMessageType::Thread => ServerMessage::Thread {
+                room: room?,
+                message_id: message_id?,
+                messages: msg.history.ok_or_else(|| malformed("history"))?,
+            },
+            WebSocketMessageType::History => ServerMessage::History {
+                room: room?,
+                messages: msg.history.ok_or_else(|| malformed("history"))?,
+            },
+            WebSocketMessageType::Typing => ServerMessage::Typing {
+                room: room?,
+                username: username?,
+                typing: msg.typing.unwrap_or(false),
+            },
+            WebSocketMessageType::Ack => ServerMessage::Ack {
+                room: room?,
+                message_id: message_id?,
+                delivered: msg.delivered.ok_or_else(|| malformed("delivered"))?,
+            },
+            WebSocketMessageType::Read => ServerMessage::Read {
+                room: room?,
+                username: username?,
+                message_id: message_id?,
+            },
+            WebSocketMessageType::UsernameChange => ServerMessage::UsernameChange { room: room.ok(), username: username? },
+            WebSocketMessageType::UserList => ServerMessage::UserList {
+                room: room?,
+                users: msg.users.ok_or_else(|| malformed("users"))?,
+                presence: BTreeMap::new(),
+            },
+            WebSocketMessageType::JoinRoom => ServerMessage::JoinRoom { room: room? },
+            WebSocketMessageType::LeaveRoom => ServerMessage::LeaveRoom { room: room? },
+            WebSocketMessageType::RoomList => ServerMessage::RoomList {
+                rooms: msg.rooms.ok_or_else(|| malformed("rooms"))?,
+            },
+            WebSocketMessageType::Error => ServerMessage::Error {
+                error: msg.error.ok_or_else(|| malformed("error"))?,
+                request_id: msg.request_id,
+            },
+            WebSocketMessageType::Hello
+            | WebSocketMessageType::Presence
+            | WebSocketMessageType::Kick
+            | WebSocketMessageType::Ban
+            | WebSocketMessageType::Unban
+            | WebSocketMessageType::Mute
+            | WebSocketMessageType::BanIp
+            | WebSocketMessageType::UnbanIp => {
+                return Err(ChatError::new(ErrorCode::Unsupported, "Servers do not send this message type"));
+            },
+        };
+        Ok(server_msg)
+    }
+}
+
+impl From<&ServerMessage> for WebSocketMessage {
+    fn from(msg: &ServerMessage) -> WebSocketMessage {
+        let base = WebSocketMessage::new(msg.kind());
+        match msg.clone() {
+            ServerMessage::Welcome { version, capabilities } => WebSocketMessage {
+                version: Some(version),
+                capabilities: Some(capabilities),
+                ..base
+            },
+            ServerMessage::NewMessage { room, message }
+            | ServerMessage::EditMessage { room, message }
+            | ServerMessage::DeleteMessage { room, message } => WebSocketMessage {
+                message_id: message.id,
+                message: Some(message),
+                room: Some(room),
+                ..base
+            },
+            ServerMessage::System { room, message } => WebSocketMessage {
+                message: Some(message),
+                room,
+                ..base
+            },
+            ServerMessage::DirectMessage { recipient, message } => WebSocketMessage {
+                message: Some(message),
+                recipient: Some(recipient),
+                ..base
+            },
+            ServerMessage::AddReaction { room, message_id, emoji, username, reactions }
+            | ServerMessage::RemoveReaction { room, message_id, emoji, username, reactions } => WebSocketMessage {
+                room: Some(room),
+                message_id: Some(message_id),
+                emoji: Some(emoji),
+                username: Some(username),
+                reactions: Some(reactions),
+                ..base
+            },
+            ServerMessage::Thread { room, message_id, messages } => WebSocketMessage {
+                room: Some(room),
+                message_id: Some(message_id),
+                history: Some(messages),
+                ..base
+            },
+            ServerMessage::History { room, messages } => WebSocketMessage {
+                room: Some(room),
+                history: Some(messages),
+                ..base
+            },
+            ServerMessage::Typing { room, username, typing } => WebSocketMessage {
+                room: Some(room),
+                username: Some(username),
+                typing: Some(typing),
+                ..base
+            },
+            ServerMessage::Ack { room, message_id, delivered } => WebSocketMessage {
+                room: Some(room),
+                message_id: Some(message_id),
+                delivered: Some(delivered),
+                ..base
+            },
+            ServerMessage::Read { room, username, message_id } => WebSocketMessage {
+                room: Some(room),
+                username: Some(username),
+                message_id: Some(message_id),
+                ..base
+            },
+            ServerMessage::UsernameChange { room, username } => WebSocketMessage {
+                room,
+                username: Some(username),
+                ..base
+            },
+            ServerMessage::UserList { room, users, .. } => WebSocketMessage {
+                room: Some(room),
+                users: Some(users),
+                ..base
+            },
+            ServerMessage::JoinRoom { room } | ServerMessage::LeaveRoom { room } => WebSocketMessage {
+                room: Some(room),
+                ..base
+            },
+            ServerMessage::RoomList { rooms } => WebSocketMessage {
+                rooms: Some(rooms),
+                ..base
+            },
+            ServerMessage::Error { error, request_id } => WebSocketMessage {
+                error: Some(error),
+                request_id,
+                ..base
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use crate::Presence;
+
+    use super::*;
+
+    fn chat_message() -> ChatMessage {
+        ChatMessage {
+            id: Some(7),
+            created_at: NaiveDate::from_ymd_opt(2024, 5, 1).unwrap().and_hms_opt(12, 0, 0).unwrap(),
+            ..ChatMessage::new("hi".to_string(), "alice".to_string())
+        }
+    }
+
+    fn encode(msg: &ServerMessage) -> serde_json::Value {
+        json!(WebSocketMessage::from(msg))
+    }
+
+    /// Every field is sent, set or not, as version 1 clients expect.
+    #[test]
+    fn encodes_flat_messages_with_every_field() {
+        let msg = ServerMessage::NewMessage { room: "general".to_string(), message: chat_message() };
+        assert_eq!(encode(&msg), json!({
+            "message_type": "NewMessage",
+            "message": {
+                "id": 7,
+                "message": "hi",
+                "author": "alice",
+                "created_at": "2024-05-01T12:00:00",
+                "edited_at": null,
+                "deleted": false,
+                "reactions": [],
+                "reply_to": null,
+            },
+            "users": null,
+            "username": null,
+            "room": "general",
+            "rooms": null,
+            "history": null,
+            "recipient": null,
+            "message_id": 7,
+            "emoji": null,
+            "reactions": null,
+            "typing": null,
+            "delivered": null,
+            "request_id": null,
+            "error": null,
+            "version": null,
+            "capabilities": null,
+        }));
+    }
+
+    #[test]
+    fn user_lists_leave_out_presence() {
+        let presence = [("bob".to_string(), Presence::default())].into();
+        let msg = ServerMessage::UserList {
+            room: "general".to_string(),
+            users: vec!["alice".to_string(), "bob".to_string()],
+            presence,
+        };
+        let value = encode(&msg);
+        assert_eq!(value["message_type"], "UserList");
+        assert_eq!(value["users"], json!(["alice", "bob"]));
+        assert!(value.get("users_presence").is_none() && value.get("presence").is_none());
+    }
+
+    /// The messages of the first release, decoded the way its client did.
+    #[test]
+    fn first_release_clients_can_read_their_messages() {
+        #[derive(Deserialize)]
+        enum FirstType {
+            NewMessage,
+            UserList,
+            UsernameChange,
+            System,
+        }
+        #[derive(Deserialize)]
+        struct FirstMessage {
+            message: String,
+            author: String,
+        }
+        #[derive(Deserialize)]
+        struct First {
+            message_type: FirstType,
+            message: Option<FirstMessage>,
+            users: Option<Vec<String>>,
+            username: Option<String>,
+        }
+
+        let decode = |msg: &ServerMessage| serde_json::from_value::<First>(encode(msg)).unwrap();
+        let first = decode(&ServerMessage::NewMessage { room: "general".to_string(), message: chat_message() });
+        assert!(matches!(first.message_type, FirstType::NewMessage));
+        let message = first.message.unwrap();
+        assert_eq!((message.message.as_str(), message.author.as_str()), ("hi", "alice"));
+
+        let first = decode(&ServerMessage::system(None, "welcome".to_string()));
+        assert!(matches!(first.message_type, FirstType::System));
+        let first = decode(&ServerMessage::UsernameChange { room: None, username: "bob".to_string() });
+        assert!(matches!(first.message_type, FirstType::UsernameChange));
+        assert_eq!(first.username.as_deref(), Some("bob"));
+        let first = decode(&ServerMessage::UserList {
+            room: "general".to_string(),
+            users: vec!["bob".to_string()],
+            presence: BTreeMap::new(),
+        });
+        assert!(matches!(first.message_type, FirstType::UserList));
+        assert_eq!(first.users, Some(vec!["bob".to_string()]));
+    }
+
+    #[test]
+    fn decodes_first_release_requests() {
+        let request = Request::decode(r#"{
+            "message_type": "NewMessage",
+            "message": {"message": "hi", "author": "me", "created_at": "2024-05-01T12:00:00"},
+            "users": null,
+            "username": null
+        }"#).unwrap();
+        assert_eq!(request.message, ClientMessage::NewMessage { room: None, text: "hi".to_string(), reply_to: None });
+
+        let request = Request::decode(r#"{"message_type": "UsernameChange", "message": null, "users": null, "username": "bob"}"#).unwrap();
+        assert_eq!(request.message, ClientMessage::UsernameChange { username: "bob".to_string() });
+    }
+
+    #[test]
+    fn decodes_version_two_requests() {
+        let request = Request::decode(r#"{"message_type": "AddReaction", "room": "general", "message_id": 7, "emoji": "👍", "request_id": "1"}"#).unwrap();
+        assert_eq!(request.request_id.as_deref(), Some("1"));
+        assert_eq!(request.message, ClientMessage::AddReaction {
+            room: Some("general".to_string()),
+            message_id: 7,
+            emoji: "👍".to_string(),
+        });
+
+        let (err, request_id) = Request::decode(r#"{"message_type": "EditMessage", "message_id": 7, "request_id": "2"}"#).unwrap_err();
+        assert_eq!((err.code, request_id.as_deref()), (ErrorCode::InvalidRequest, Some("2")));
+        assert_eq!(err.request_type, Some(WebSocketMessageType::EditMessage));
+    }
+
+    #[test]
+    fn presence_and_moderation_need_version_three() {
+        for request in [
+            r#"{"message_type": "Presence", "presence": {"status": "away"}}"#,
+            r#"{"message_type": "Ban", "username": "bob", "ban_ip": true}"#,
+            r#"{"message_type": "Kick", "username": "bob"}"#,
+        ] {
+            assert_eq!(Request::decode(request).unwrap_err().0.code, ErrorCode::Unsupported, "{}", request);
+        }
+    }
+
+    #[test]
+    fn server_messages_survive_a_round_trip() {
+        let room = "general".to_string();
+        let messages = [
+            ServerMessage::Welcome { version: 2, capabilities: vec!["edits".to_string()] },
+            ServerMessage::NewMessage { room: room.clone(), message: chat_message() },
+            ServerMessage::System { room: Some(room.clone()), message: chat_message() },
+            ServerMessage::DirectMessage { recipient: "bob".to_string(), message: chat_message() },
+            ServerMessage::EditMessage { room: room.clone(), message: chat_message() },
+            ServerMessage::DeleteMessage { room: room.clone(), message: chat_message() },
+            ServerMessage::AddReaction {
+                room: room.clone(),
+                message_id: 7,
+                emoji: "👍".to_string(),
+                username: "bob".to_string(),
+                reactions: vec![Reaction { emoji: "👍".to_string(), users: vec!["bob".to_string()] }],
+            },
+            ServerMessage::Thread { room: room.clone(), message_id: 7, messages: vec![chat_message()] },
+            ServerMessage::History { room: room.clone(), messages: vec![chat_message()] },
+            ServerMessage::Typing { room: room.clone(), username: "bob".to_string(), typing: true },
+            ServerMessage::Ack { room: room.clone(), message_id: 7, delivered: 2 },
+            ServerMessage::Read { room: room.clone(), username: "bob".to_string(), message_id: 7 },
+            ServerMessage::UsernameChange { room: None, username: "bob".to_string() },
+            ServerMessage::UserList { room: room.clone(), users: vec!["bob".to_string()], presence: BTreeMap::new() },
+            ServerMessage::JoinRoom { room: room.clone() },
+            ServerMessage::LeaveRoom { room: room.clone() },
+            ServerMessage::RoomList { rooms: vec![room.clone()] },
+            ServerMessage::Error {
+                error: ChatError::new(ErrorCode::NotFound, "No such message"),
+                request_id: Some("3".to_string()),
+            },
+        ];
+        for msg in messages {
+            let text = WebSocketMessage::from(&msg).to_string();
+            assert_eq!(ServerMessage::decode(&text).unwrap(), msg, "{}", text);
+        }
+    }
+}
@@ -1,88 +1,625 @@
+use std::{collections::BTreeMap, fmt};
+
 use chrono::{NaiveDateTime, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
+pub mod legacy;
+
+/// Version of the protocol spoken by this build. Clients announce theirs
+/// in `Hello`, the server answers with the version both understand.
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// First version whose messages are tagged `ClientMessage`s and
+/// `ServerMessage`s. Older versions use the flat `legacy::WebSocketMessage`.
+pub const TAGGED_PROTOCOL_VERSION: u32 = 3;
+
+/// Optional features a client can announce in `Hello`.
+pub mod capabilities {
+    pub const EDITS: &str = "edits";
+    pub const REACTIONS: &str = "reactions";
+    pub const THREADS: &str = "threads";
+    pub const TYPING: &str = "typing";
+    /// Acks of sent messages and read markers.
+    pub const RECEIPTS: &str = "receipts";
+    pub const ERRORS: &str = "errors";
+    /// Presence of users in user lists, and setting your own.
+    pub const PRESENCE: &str = "presence";
+}
+
+/// Every capability this build supports.
+pub const CAPABILITIES: &[&str] = &[
+    capabilities::EDITS,
+    capabilities::REACTIONS,
+    capabilities::THREADS,
+    capabilities::TYPING,
+    capabilities::RECEIPTS,
+    capabilities::ERRORS,
+    capabilities::PRESENCE,
+];
+
+/// Encoding of messages, chosen with the `Sec-WebSocket-Protocol` header.
+/// JSON goes in text frames, MessagePack in binary ones.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum WireFormat {
+    #[default]
+    Json,
+    MessagePack,
+}
+
+impl WireFormat {
+    pub fn subprotocol(&self) -> &'static str {
+        match self {
+            WireFormat::Json => "json",
+            WireFormat::MessagePack => "msgpack",
+        }
+    }
 
-#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+    pub fn from_subprotocol(name: &str) -> Option<WireFormat> {
+        match name {
+            "json" => Some(WireFormat::Json),
+            "msgpack" => Some(WireFormat::MessagePack),
+            _ => None,
+        }
+    }
+}
+
+/// Kind of a message, the tag of `ClientMessage` and `ServerMessage`.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
 pub enum WebSocketMessageType {
     NewMessage,
     UserList,
     UsernameChange,
     System,
+    JoinRoom,
+    LeaveRoom,
+    RoomList,
+    History,
+    DirectMessage,
+    EditMessage,
+    DeleteMessage,
+    AddReaction,
+    RemoveReaction,
+    Thread,
+    Typing,
+    Ack,
+    Read,
+    Error,
+    Hello,
+    Welcome,
+    Presence,
+    Kick,
+    Ban,
+    Unban,
+    Mute,
+    BanIp,
+    UnbanIp,
+}
+
+impl WebSocketMessageType {
+    /// Capability a client needs to receive messages of this type.
+    pub fn capability(&self) -> Option<&'static str> {
+        match self {
+            WebSocketMessageType::EditMessage | WebSocketMessageType::DeleteMessage => Some(capabilities::EDITS),
+            WebSocketMessageType::AddReaction | WebSocketMessageType::RemoveReaction => Some(capabilities::REACTIONS),
+            WebSocketMessageType::Thread => Some(capabilities::THREADS),
+            WebSocketMessageType::Typing => Some(capabilities::TYPING),
+            WebSocketMessageType::Ack | WebSocketMessageType::Read => Some(capabilities::RECEIPTS),
+            WebSocketMessageType::Error => Some(capabilities::ERRORS),
+            WebSocketMessageType::Presence => Some(capabilities::PRESENCE),
+            _ => None,
+        }
+    }
+}
+
+/// Machine readable reason of an `Error` message.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The payload is not a valid message.
+    InvalidMessage,
+    /// The message is valid but its fields are missing or wrong.
+    InvalidRequest,
+    InvalidUsername,
+    NotInRoom,
+    NotFound,
+    Forbidden,
+    /// Clients cannot send this message type.
+    Unsupported,
+    /// The client's protocol version is too old; the connection is closed.
+    UnsupportedVersion,
+    /// Too many requests in a short time; repeat offenders are muted.
+    RateLimited,
+    Internal,
+}
+
+/// Why a request failed. Only sent to the client that made it.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ChatError {
+    pub code: ErrorCode,
+    pub text: String,
+    /// Type of the failed request, if it could be parsed.
+    #[serde(default)]
+    pub request_type: Option<WebSocketMessageType>,
+}
+
+impl ChatError {
+    pub fn new(code: ErrorCode, text: impl Into<String>) -> ChatError {
+        ChatError {
+            code,
+            text: text.into(),
+            request_type: None,
+        }
+    }
+}
+
+impl fmt::Display for ChatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.text)
+    }
+}
+
+/// Message sent by a client. Requests without a `room` go to the room the
+/// socket was opened on.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(tag = "type")]
+pub enum ClientMessage {
+    /// First message of a connection, announcing what the client speaks.
+    Hello {
+        version: u32,
+        #[serde(default)]
+        capabilities: Vec<String>,
+    },
+    NewMessage {
+        room: Option<String>,
+        text: String,
+        /// Id of the message this one replies to.
+        reply_to: Option<u64>,
+    },
+    DirectMessage {
+        recipient: String,
+        text: String,
+    },
+    /// Replaces the text of one of your messages.
+    EditMessage {
+        room: Option<String>,
+        message_id: u64,
+        text: String,
+    },
+    /// Deletes one of your messages.
+    DeleteMessage {
+        room: Option<String>,
+        message_id: u64,
+    },
+    AddReaction {
+        room: Option<String>,
+        message_id: u64,
+        emoji: String,
+    },
+    RemoveReaction {
+        room: Option<String>,
+        message_id: u64,
+        emoji: String,
+    },
+    /// Asks for a message and all replies to it.
+    Thread {
+        room: Option<String>,
+        message_id: u64,
+    },
+    /// Tells that the user started (`true`) or stopped (`false`) typing.
+    Typing {
+        room: Option<String>,
+        typing: bool,
+    },
+    /// Marks every message of the room up to `message_id` as read.
+    Read {
+        room: Option<String>,
+        message_id: u64,
+    },
+    UsernameChange {
+        username: String,
+    },
+    UserList {
+        room: Option<String>,
+    },
+    /// Sets the presence shown next to your name in user lists.
+    Presence {
+        status: PresenceStatus,
+        #[serde(default)]
+        text: Option<String>,
+    },
+    JoinRoom {
+        room: String,
+    },
+    LeaveRoom {
+        room: String,
+    },
+    RoomList,
+    /// Disconnects every connection of a user. Moderators only.
+    Kick {
+        username: String,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    /// Bans a user for `duration` seconds, or for good without one. With
+    /// `ip` the addresses they are connected from are banned as well.
+    /// Moderators only.
+    Ban {
+        username: String,
+        #[serde(default)]
+        duration: Option<u64>,
+        #[serde(default)]
+        ip: bool,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    /// Lifts every ban of a user. Moderators only.
+    Unban {
+        username: String,
+    },
+    /// Bans an address for `duration` seconds, or for good without one.
+    /// Moderators only.
+    BanIp {
+        ip: String,
+        #[serde(default)]
+        duration: Option<u64>,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    /// Lifts every ban of an address. Moderators only.
+    UnbanIp {
+        ip: String,
+    },
+    /// Keeps a user from sending messages for `duration` seconds; `0`
+    /// lifts a mute. Moderators only.
+    Mute {
+        username: String,
+        duration: u64,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+}
+
+impl ClientMessage {
+    pub fn kind(&self) -> WebSocketMessageType {
+        match self {
+            ClientMessage::Hello { .. } => WebSocketMessageType::Hello,
+            ClientMessage::NewMessage { .. } => WebSocketMessageType::NewMessage,
+            ClientMessage::DirectMessage { .. } => WebSocketMessageType::DirectMessage,
+            ClientMessage::EditMessage { .. } => WebSocketMessageType::EditMessage,
+            ClientMessage::DeleteMessage { .. } => WebSocketMessageType::DeleteMessage,
+            ClientMessage::AddReaction { .. } => WebSocketMessageType::AddReaction,
+            ClientMessage::RemoveReaction { .. } => WebSocketMessageType::RemoveReaction,
+            ClientMessage::Thread { .. } => WebSocketMessageType::Thread,
+            ClientMessage::Typing { .. } => WebSocketMessageType::Typing,
+            ClientMessage::Read { .. } => WebSocketMessageType::Read,
+            ClientMessage::UsernameChange { .. } => WebSocketMessageType::UsernameChange,
+            ClientMessage::UserList { .. } => WebSocketMessageType::UserList,
+            ClientMessage::Presence { .. } => WebSocketMessageType::Presence,
+            ClientMessage::JoinRoom { .. } => WebSocketMessageType::JoinRoom,
+            ClientMessage::LeaveRoom { .. } => WebSocketMessageType::LeaveRoom,
+            ClientMessage::RoomList => WebSocketMessageType::RoomList,
+            ClientMessage::Kick { .. } => WebSocketMessageType::Kick,
+            ClientMessage::Ban { .. } => WebSocketMessageType::Ban,
+            ClientMessage::Unban { .. } => WebSocketMessageType::Unban,
+            ClientMessage::BanIp { .. } => WebSocketMessageType::BanIp,
+            ClientMessage::UnbanIp { .. } => WebSocketMessageType::UnbanIp,
+            ClientMessage::Mute { .. } => WebSocketMessageType::Mute,
+        }
+    }
+}
+
+impl fmt::Display for ClientMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", json!(self))
+    }
+}
+
+/// A client message with the id echoed in errors about it.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Request {
+    #[serde(default)]
+    pub request_id: Option<String>,
+    #[serde(flatten)]
+    pub message: ClientMessage,
+}
+
+impl Request {
+    /// Decodes a tagged request or a flat one of protocol versions 1 and
+    /// 2. Errors carry the `request_id` of the payload, if it has one.
+    pub fn decode(text: &str) -> Result<Request, (ChatError, Option<String>)> {
+        let value = serde_json::from_str(text).map_err(|err| (invalid_message(err), None))?;
+        Request::from_value(value)
+    }
+
+    /// Like `decode`, for a MessagePack payload.
+    pub fn decode_msgpack(bytes: &[u8]) -> Result<Request, (ChatError, Option<String>)> {
+        let value = rmp_serde::from_slice(bytes).map_err(|err| (invalid_message(err), None))?;
+        Request::from_value(value)
+    }
+
+    fn from_value(value: serde_json::Value) -> Result<Request, (ChatError, Option<String>)> {
+        let request_id = value.get("request_id")
+            .and_then(|id| id.as_str())
+            .map(str::to_string);
+        let request = if value.get("message_type").is_some() {
+            serde_json::from_value::<legacy::WebSocketMessage>(value.clone())
+                .map_err(invalid_message)
+                .and_then(Request::try_from)
+        } else {
+            serde_json::from_value(value.clone()).map_err(invalid_message)
+        };
+        request.map_err(|mut err| {
+            err.request_type = message_kind(&value);
+            (err, request_id)
+        })
+    }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-pub struct WebSocketMessage {
-    pub message_type: WebSocketMessageType,
-    pub message: Option<ChatMessage>,
-    pub users: Option<Vec<String>>,
-    pub username: Option<String>,
+/// Message sent by the server.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(tag = "type")]
+pub enum ServerMessage {
+    /// Answer to `Hello` with the version and capabilities both sides share.
+    Welcome {
+        version: u32,
+        capabilities: Vec<String>,
+    },
+    NewMessage {
+        room: String,
+        message: ChatMessage,
+    },
+    /// Announcement of the server. Without a room it concerns the connection.
+    System {
+        room: Option<String>,
+        message: ChatMessage,
+    },
+    /// Sent to the recipient and echoed back to the author.
+    DirectMessage {
+        recipient: String,
+        message: ChatMessage,
+    },
+    EditMessage {
+        room: String,
+        message: ChatMessage,
+    },
+    /// Tombstone of a deleted message.
+    DeleteMessage {
+        room: String,
+        message: ChatMessage,
+    },
+    /// Who reacted and the resulting reactions of the message.
+    AddReaction {
+        room: String,
+        message_id: u64,
+        emoji: String,
+        username: String,
+        reactions: Vec<Reaction>,
+    },
+    RemoveReaction {
+        room: String,
+        message_id: u64,
+        emoji: String,
+        username: String,
+        reactions: Vec<Reaction>,
+    },
+    /// The parent message followed by its replies.
+    Thread {
+        room: String,
+        message_id: u64,
+        messages: Vec<ChatMessage>,
+    },
+    History {
+        room: String,
+        messages: Vec<ChatMessage>,
+    },
+    Typing {
+        room: String,
+        username: String,
+        typing: bool,
+    },
+    /// Tells the sender that its message was accepted under `message_id`
+    /// and queued for `delivered` other connections of the sender's
+    /// instance. Users on other instances get it over the bus and are not
+    /// counted.
+    Ack {
+        room: String,
+        message_id: u64,
+        delivered: usize,
+    },
+    /// How far a user read the room. Sent on joining, and later to the
+    /// authors of the messages the user just read.
+    Read {
+        room: String,
+        username: String,
+        message_id: u64,
+    },
+    /// Username of the connection, or of the user in `room`.
+    UsernameChange {
+        room: Option<String>,
+        username: String,
+    },
+    UserList {
+        room: String,
+        users: Vec<String>,
+        /// Presence of the users that are not simply online.
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        presence: BTreeMap<String, Presence>,
+    },
+    JoinRoom {
+        room: String,
+    },
+    LeaveRoom {
+        room: String,
+    },
+    RoomList {
+        rooms: Vec<String>,
+    },
+    /// Error about the request with `request_id`.
+    Error {
+        error: ChatError,
+        request_id: Option<String>,
+    },
 }
 
-impl WebSocketMessage {
-    pub fn from_chat_msg(message: ChatMessage) -> WebSocketMessage {
-        WebSocketMessage {
-            message_type: WebSocketMessageType::NewMessage,
-            message: Some(message),
-            users: None,
-            username: None,
+impl ServerMessage {
+    pub fn system(room: Option<String>, text: String) -> ServerMessage {
+        ServerMessage::System {
+            room,
+            message: ChatMessage::new(text, "system".to_string()),
         }
     }
 
-    pub fn from_users_list(users: Vec<String>) -> WebSocketMessage {
-        WebSocketMessage {
-            message_type: WebSocketMessageType::UserList,
-            message: None,
-            users: Some(users),
-            username: None,
+    /// Broadcast of an edited message or a tombstone of a deleted one.
+    pub fn updated(room: String, message: ChatMessage) -> ServerMessage {
+        if message.deleted {
+            ServerMessage::DeleteMessage { room, message }
+        } else {
+            ServerMessage::EditMessage { room, message }
         }
     }
 
-    pub fn from_username(username: String) -> WebSocketMessage {
-        WebSocketMessage {
-            message_type: WebSocketMessageType::UsernameChange,
-            message: None,
-            users: None,
-            username: Some(username),
+    pub fn kind(&self) -> WebSocketMessageType {
+        match self {
+            ServerMessage::Welcome { .. } => WebSocketMessageType::Welcome,
+            ServerMessage::NewMessage { .. } => WebSocketMessageType::NewMessage,
+            ServerMessage::System { .. } => WebSocketMessageType::System,
+            ServerMessage::DirectMessage { .. } => WebSocketMessageType::DirectMessage,
+            ServerMessage::EditMessage { .. } => WebSocketMessageType::EditMessage,
+            ServerMessage::DeleteMessage { .. } => WebSocketMessageType::DeleteMessage,
+            ServerMessage::AddReaction { .. } => WebSocketMessageType::AddReaction,
+            ServerMessage::RemoveReaction { .. } => WebSocketMessageType::RemoveReaction,
+            ServerMessage::Thread { .. } => WebSocketMessageType::Thread,
+            ServerMessage::History { .. } => WebSocketMessageType::History,
+            ServerMessage::Typing { .. } => WebSocketMessageType::Typing,
+            ServerMessage::Ack { .. } => WebSocketMessageType::Ack,
+            ServerMessage::Read { .. } => WebSocketMessageType::Read,
+            ServerMessage::UsernameChange { .. } => WebSocketMessageType::UsernameChange,
+            ServerMessage::UserList { .. } => WebSocketMessageType::UserList,
+            ServerMessage::JoinRoom { .. } => WebSocketMessageType::JoinRoom,
+            ServerMessage::LeaveRoom { .. } => WebSocketMessageType::LeaveRoom,
+            ServerMessage::RoomList { .. } => WebSocketMessageType::RoomList,
+            ServerMessage::Error { .. } => WebSocketMessageType::Error,
         }
     }
 
-    pub fn from_system_msg(message: String) -> WebSocketMessage {
-        let message = ChatMessage::new(message, "system".to_string());
-        WebSocketMessage {
-            message_type: WebSocketMessageType::System,
-            message: Some(message),
-            users: None,
-            username: None,
+    /// Decodes a tagged message or a flat one of protocol versions 1 and 2.
+    pub fn decode(text: &str) -> Result<ServerMessage, ChatError> {
+        ServerMessage::from_value(serde_json::from_str(text).map_err(invalid_message)?)
+    }
+
+    /// Like `decode`, for a MessagePack payload.
+    pub fn decode_msgpack(bytes: &[u8]) -> Result<ServerMessage, ChatError> {
+        ServerMessage::from_value(rmp_serde::from_slice(bytes).map_err(invalid_message)?)
+    }
+
+    fn from_value(value: serde_json::Value) -> Result<ServerMessage, ChatError> {
+        if value.get("message_type").is_some() {
+            let msg: legacy::WebSocketMessage = serde_json::from_value(value).map_err(invalid_message)?;
+            ServerMessage::try_from(msg)
+        } else {
+            serde_json::from_value(value).map_err(invalid_message)
         }
     }
 }
 
-impl ToString for WebSocketMessage {
-    fn to_string(&self) -> String {
-        json!(self).to_string()
+impl fmt::Display for ServerMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", json!(self))
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, PartialEq)]
+fn invalid_message(err: impl fmt::Display) -> ChatError {
+    ChatError::new(ErrorCode::InvalidMessage, format!("Cannot parse message: {}", err))
+}
+
+/// Kind named by the tag of a tagged or flat message.
+fn message_kind(value: &serde_json::Value) -> Option<WebSocketMessageType> {
+    let tag = value.get("type").or_else(|| value.get("message_type"))?;
+    serde_json::from_value(tag.clone()).ok()
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub struct ChatMessage {
+    /// Assigned by the server once the message is stored.
+    #[serde(default)]
+    pub id: Option<u64>,
     pub message: String,
     pub author: String,
     pub created_at: NaiveDateTime,
+    #[serde(default)]
+    pub edited_at: Option<NaiveDateTime>,
+    #[serde(default)]
+    pub deleted: bool,
+    #[serde(default)]
+    pub reactions: Vec<Reaction>,
+    /// Id of the message this one replies to.
+    #[serde(default)]
+    pub reply_to: Option<u64>,
 }
 
 impl ChatMessage {
     pub fn new(message: String, author: String) -> ChatMessage {
         ChatMessage {
+            id: None,
             message,
             author,
             created_at: Utc::now().naive_utc(),
+            edited_at: None,
+            deleted: false,
+            reactions: Vec::new(),
+            reply_to: None,
         }
     }
+
+    pub fn reply(message: String, author: String, reply_to: u64) -> ChatMessage {
+        ChatMessage {
+            reply_to: Some(reply_to),
+            ..ChatMessage::new(message, author)
+        }
+    }
+
+    pub fn edit(&mut self, text: String) {
+        self.message = text;
+        self.edited_at = Some(Utc::now().naive_utc());
+    }
+
+    /// Turns the message into a tombstone, dropping its text.
+    pub fn delete(&mut self) {
+        self.message = String::new();
+        self.deleted = true;
+    }
 }
 
-impl ToString for ChatMessage {
-    fn to_string(&self) -> String {
-        json!(self).to_string()
+impl fmt::Display for ChatMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", json!(self))
     }
 }
+
+/// How present a user is, ordered from most to least present.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceStatus {
+    #[default]
+    Online,
+    /// At the desk but busy.
+    Dnd,
+    Away,
+}
+
+/// Presence of a user as shown in user lists.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug, Default)]
+pub struct Presence {
+    pub status: PresenceStatus,
+    /// Custom status, e.g. "in a meeting".
+    #[serde(default)]
+    pub text: Option<String>,
+}
+
+/// Users who reacted to a message with the same emoji.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Reaction {
+    pub emoji: String,
+    pub users: Vec<String>,
+}
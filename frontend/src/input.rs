@@ -1,22 +1,72 @@
+use chrono::{DateTime, TimeDelta, Utc};
 use web_sys::HtmlTextAreaElement;
 use yew::prelude::*;
 
-use common::{ChatMessage, WebSocketMessage, WebSocketMessageType};
+use common::{ClientMessage, WebSocketMessageType};
+
+/// How often a typing notice is repeated while the user keeps typing.
+/// Has to be below the server's typing timeout.
+const TYPING_REFRESH_SECS: i64 = 3;
 
 #[derive(PartialEq, Properties)]
 pub struct InputProps {
-    pub callback: Callback<WebSocketMessage>,
+    pub callback: Callback<ClientMessage>,
     pub message_type: WebSocketMessageType,
     pub wrapper_name: String,
     pub placeholder: String,
     pub button_text: String,
+    #[prop_or_default]
+    pub room: Option<String>,
+    #[prop_or_default]
+    pub recipient: Option<String>,
+    #[prop_or_default]
+    pub reply_to: Option<u64>,
+    /// Receives `true` while the user is typing and `false` once the input is cleared.
+    #[prop_or_default]
+    pub on_typing: Option<Callback<bool>>,
 }
 
 #[function_component(Input)]
 pub fn get_input(props: &InputProps) -> Html {
-    let InputProps { callback, message_type, wrapper_name, placeholder, button_text } = props;
+    let InputProps {
+        callback,
+        message_type,
+        wrapper_name,
+        placeholder,
+        button_text,
+        room,
+        recipient,
+        reply_to,
+        on_typing,
+    } = props;
     let new_value_handle = use_state(String::default);
     let new_value = (*new_value_handle).clone();
+    let typing_sent_at = use_mut_ref(Option::<DateTime<Utc>>::default);
+
+    let cloned_typing_sent_at = typing_sent_at.clone();
+    let cloned_on_typing = on_typing.clone();
+    let on_input = Callback::from(move |e: InputEvent| {
+        let on_typing = match &cloned_on_typing {
+            Some(on_typing) => on_typing,
+            None => return,
+        };
+        let value = e.target_dyn_into::<HtmlTextAreaElement>()
+            .map(|text_area| text_area.value())
+            .unwrap_or_default();
+        let mut sent_at = cloned_typing_sent_at.borrow_mut();
+        if value.is_empty() {
+            if sent_at.take().is_some() {
+                on_typing.emit(false);
+            }
+            return;
+        }
+        let now = Utc::now();
+        let refresh = TimeDelta::try_seconds(TYPING_REFRESH_SECS).unwrap_or_default();
+        if sent_at.is_none_or(|at| now - at >= refresh) {
+            *sent_at = Some(now);
+            on_typing.emit(true);
+        }
+    });
 
     let cloned_new_value_handle = new_value_handle.clone();
     let on_value_change = Callback::from(move |e: Event| {
@@ -28,6 +78,9 @@ pub fn get_input(props: &InputProps) -> Html {
 
     let cloned_new_value = new_value.clone();
     let cloned_message_type = message_type.clone();
+    let cloned_room = room.clone();
+    let cloned_recipient = recipient.clone();
+    let cloned_reply_to = *reply_to;
     let callback = callback.clone();
 
     let on_button_click = Callback::from(move |_: MouseEvent| {
@@ -35,16 +88,26 @@ pub fn get_input(props: &InputProps) -> Html {
             return;
         }
         let msg = match cloned_message_type {
-            WebSocketMessageType::NewMessage => {
-                WebSocketMessage::from_chat_msg(
-                    ChatMessage::new(cloned_new_value.clone(), "".to_string())
-                )
+            WebSocketMessageType::NewMessage => ClientMessage::NewMessage {
+                room: cloned_room.clone(),
+                text: cloned_new_value.clone(),
+                reply_to: cloned_reply_to,
             },
-            WebSocketMessageType::UsernameChange => {
-                if cloned_new_value.to_lowercase() == "system" {
-                    return;
+            WebSocketMessageType::DirectMessage => {
+                let recipient = match cloned_recipient.clone() {
+                    Some(recipient) => recipient,
+                    None => return,
+                };
+                ClientMessage::DirectMessage {
+                    recipient,
+                    text: cloned_new_value.clone(),
                 }
-                WebSocketMessage::from_username(cloned_new_value.clone())
+            },
+            WebSocketMessageType::UsernameChange => ClientMessage::UsernameChange {
+                username: cloned_new_value.clone(),
+            },
+            WebSocketMessageType::JoinRoom => ClientMessage::JoinRoom {
+                room: cloned_new_value.trim().to_string(),
             },
             _ => {
                 return;
@@ -53,6 +116,8 @@ pub fn get_input(props: &InputProps) -> Html {
         };
         callback.emit(msg);
         new_value_handle.set("".to_string());
+        // The server ends typing once the message is sent.
+        typing_sent_at.borrow_mut().take();
     });
 
     html! {
@@ -63,6 +128,7 @@ pub fn get_input(props: &InputProps) -> Html {
                 class="text-input"
                 value={new_value}
                 onchange={on_value_change}
+                oninput={on_input}
             ></textarea>
             <button type="submit" class="btn" onclick={on_button_click}>
                 {button_text}
@@ -1,34 +1,150 @@
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{TimeDelta, Utc};
 use yew::prelude::*;
-use yew_hooks::use_websocket;
+use yew_hooks::{use_event_with_window, use_interval, use_timeout, use_websocket, UseWebSocketReadyState};
 
-use common::{WebSocketMessage, WebSocketMessageType};
+use common::{
+    ChatError, ChatMessage, ClientMessage, Presence, PresenceStatus, Reaction, ServerMessage, WebSocketMessageType,
+    CAPABILITIES, PROTOCOL_VERSION,
+};
 
 use crate::message_list::MessageList;
+use crate::presence::PresencePicker;
+use crate::rooms_list::RoomsList;
 use crate::users_list::UsersList;
 use crate::input::Input;
 
 mod message_list;
+mod presence;
+mod rooms_list;
 mod users_list;
 mod input;
 
+const DEFAULT_ROOM: &str = "general";
+/// How long an error banner stays visible.
+const ERROR_TIMEOUT_MS: u32 = 5000;
+/// Minutes without input after which an online user is shown as away.
+const AUTO_AWAY_MINUTES: i64 = 5;
+const AUTO_AWAY_CHECK_MS: u32 = 30_000;
+/// How often the read marker of the active room is sent at most.
+const READ_INTERVAL_MS: u32 = 2000;
+
+fn presence_msg(presence: &Presence) -> ClientMessage {
+    ClientMessage::Presence {
+        status: presence.status,
+        text: presence.text.clone(),
+    }
+}
+
+/// Token to authenticate with, taken from the `token` query parameter of the page.
+fn page_token() -> Option<String> {
+    let search = web_sys::window()?.location().search().ok()?;
+    search.trim_start_matches('?')
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("token="))
+        .map(str::to_string)
+}
+
+/// Replaces a message with its updated version. Returns whether it was found.
+fn replace_message(messages: &mut [ChatMessage], msg: ChatMessage) -> bool {
+    match messages.iter_mut().find(|m| m.id.is_some() && m.id == msg.id) {
+        Some(stored) => {
+            *stored = msg;
+            true
+        },
+        None => false,
+    }
+}
+
+/// Updates reactions of a message. Returns whether it was found.
+fn set_reactions(messages: &mut [ChatMessage], message_id: u64, reactions: Vec<Reaction>) -> bool {
+    match messages.iter_mut().find(|m| m.id == Some(message_id)) {
+        Some(stored) => {
+            stored.reactions = reactions;
+            true
+        },
+        None => false,
+    }
+}
+
 
 #[function_component]
 fn App() -> Html {
-    let messages_handle = use_state(Vec::default);
+    let messages_handle = use_state(HashMap::<String, Vec<ChatMessage>>::default);
     let messages = (*messages_handle).clone();
 
-    let users_handle = use_state(Vec::default);
+    let users_handle = use_state(HashMap::<String, Vec<String>>::default);
     let users = (*users_handle).clone();
 
+    let users_presence_handle = use_state(HashMap::<String, BTreeMap<String, Presence>>::default);
+    let users_presence = (*users_presence_handle).clone();
+
+    let presence_handle = use_state(Presence::default);
+    let presence = (*presence_handle).clone();
+    // Set while the user is shown as away for being idle.
+    let auto_away = use_mut_ref(|| false);
+    let last_active = use_mut_ref(Utc::now);
+
     let username_handle = use_state(String::default);
     let username = (*username_handle).clone();
 
-    let ws = use_websocket("ws://127.0.0.1:8000".to_string());
+    let rooms_handle = use_state(Vec::<String>::default);
+    let rooms = (*rooms_handle).clone();
+
+    let active_room_handle = use_state(|| DEFAULT_ROOM.to_string());
+    let active_room = (*active_room_handle).clone();
+
+    let direct_messages_handle = use_state(HashMap::<String, Vec<ChatMessage>>::default);
+    let direct_messages = (*direct_messages_handle).clone();
+
+    let active_direct_handle = use_state(Option::<String>::default);
+    let active_direct = (*active_direct_handle).clone();
+
+    let active_thread_handle = use_state(Option::<u64>::default);
+    let active_thread = *active_thread_handle;
+
+    let thread_messages_handle = use_state(Vec::<ChatMessage>::default);
+    let thread_messages = (*thread_messages_handle).clone();
+
+    let typing_users_handle = use_state(HashMap::<String, Vec<String>>::default);
+    let typing_users = (*typing_users_handle).clone();
+
+    let delivered_handle = use_state(HashMap::<u64, usize>::default);
+    let delivered = (*delivered_handle).clone();
+
+    let read_markers_handle = use_state(HashMap::<String, HashMap<String, u64>>::default);
+    let read_markers = (*read_markers_handle).clone();
+
+    let error_handle = use_state(Option::<ChatError>::default);
+    let error = (*error_handle).clone();
+
+    let cloned_error_handle = error_handle.clone();
+    let error_timeout = use_timeout(move || cloned_error_handle.set(None), ERROR_TIMEOUT_MS);
+
+    let ws_url = format!("ws://127.0.0.1:8000/ws/{}", DEFAULT_ROOM);
+    let ws = use_websocket(match page_token() {
+        Some(token) => format!("{}?token={}", ws_url, token),
+        None => ws_url,
+    });
 
     let mut cloned_messages = messages.clone();
+    let mut cloned_users = users.clone();
+    let mut cloned_users_presence = users_presence.clone();
+    let mut cloned_rooms = rooms.clone();
+    let cloned_active_room = active_room.clone();
+    let cloned_active_room_handle = active_room_handle.clone();
+    let mut cloned_direct_messages = direct_messages.clone();
+    let cloned_username = username.clone();
+    let mut cloned_thread_messages = thread_messages.clone();
+    let cloned_thread_messages_handle = thread_messages_handle.clone();
+    let mut cloned_typing_users = typing_users.clone();
+    let mut cloned_delivered = delivered.clone();
+    let mut cloned_read_markers = read_markers.clone();
+    let cloned_error_handle = error_handle.clone();
     use_effect_with(ws.message.clone(), move |ws_msg| {
         if let Some(msg) = &**ws_msg {
-            let websocket_message: WebSocketMessage = match serde_json::from_str(msg) {
+            let server_message = match ServerMessage::decode(msg) {
                 Ok(msg) => msg,
                 Err(err) => {
                     // TODO: add logs
@@ -36,49 +152,380 @@ fn App() -> Html {
                     return;
                 }
             };
-            match websocket_message.message_type {
-                WebSocketMessageType::NewMessage | WebSocketMessageType::System => {
-                    if let Some(msg) = websocket_message.message {
-                        cloned_messages.push(msg);
+            match server_message {
+                ServerMessage::NewMessage { room, message: msg } | ServerMessage::System { room: Some(room), message: msg } => {
+                    if msg.reply_to.is_some() && msg.reply_to == active_thread {
+                        cloned_thread_messages.push(msg.clone());
+                        cloned_thread_messages_handle.set(cloned_thread_messages);
+                    }
+                    if let Some(typing) = cloned_typing_users.get_mut(&room) {
+                        if typing.contains(&msg.author) {
+                            typing.retain(|u| u != &msg.author);
+                            typing_users_handle.set(cloned_typing_users);
+                        }
+                    }
+                    cloned_messages.entry(room).or_default().push(msg);
+                    messages_handle.set(cloned_messages);
+                },
+                ServerMessage::System { room: None, message: msg } => {
+                    cloned_messages.entry(cloned_active_room).or_default().push(msg);
+                    messages_handle.set(cloned_messages);
+                },
+                ServerMessage::UserList { room, users, presence } => {
+                    cloned_users.insert(room.clone(), users);
+                    users_handle.set(cloned_users);
+                    cloned_users_presence.insert(room, presence);
+                    users_presence_handle.set(cloned_users_presence);
+                },
+                ServerMessage::UsernameChange { username, .. } => {
+                    username_handle.set(username);
+                },
+                ServerMessage::JoinRoom { room } => {
+                    if !cloned_rooms.contains(&room) {
+                        cloned_rooms.push(room.clone());
+                        rooms_handle.set(cloned_rooms);
+                    }
+                    cloned_active_room_handle.set(room);
+                },
+                ServerMessage::LeaveRoom { room } => {
+                    cloned_rooms.retain(|r| r != &room);
+                    cloned_messages.remove(&room);
+                    cloned_users.remove(&room);
+                    cloned_users_presence.remove(&room);
+                    users_presence_handle.set(cloned_users_presence);
+                    cloned_read_markers.remove(&room);
+                    read_markers_handle.set(cloned_read_markers);
+                    if *cloned_active_room_handle == room {
+                        cloned_active_room_handle.set(cloned_rooms.first().cloned().unwrap_or_default());
+                    }
+                    rooms_handle.set(cloned_rooms);
+                    messages_handle.set(cloned_messages);
+                    users_handle.set(cloned_users);
+                },
+                ServerMessage::History { room, messages } => {
+                    cloned_messages.insert(room, messages);
+                    messages_handle.set(cloned_messages);
+                },
+                ServerMessage::DirectMessage { recipient, message: msg } => {
+                    let peer = if msg.author == cloned_username { recipient } else { msg.author.clone() };
+                    cloned_direct_messages.entry(peer).or_default().push(msg);
+                    direct_messages_handle.set(cloned_direct_messages);
+                },
+                ServerMessage::EditMessage { room, message: msg } | ServerMessage::DeleteMessage { room, message: msg } => {
+                    if replace_message(&mut cloned_thread_messages, msg.clone()) {
+                        cloned_thread_messages_handle.set(cloned_thread_messages);
+                    }
+                    let room_messages = cloned_messages.entry(room).or_default();
+                    if replace_message(room_messages, msg) {
                         messages_handle.set(cloned_messages);
-                    } else {
-                        // TODO: add logs
-                        println!("Missing message payload");
                     }
                 },
-                WebSocketMessageType::UserList => {
-                    if let Some(users) = websocket_message.users {
-                        users_handle.set(users);
-                    } else {
-                        // TODO: add logs
-                        println!("Missing users payload");
+                ServerMessage::AddReaction { room, message_id, reactions, .. }
+                | ServerMessage::RemoveReaction { room, message_id, reactions, .. } => {
+                    if set_reactions(&mut cloned_thread_messages, message_id, reactions.clone()) {
+                        cloned_thread_messages_handle.set(cloned_thread_messages);
+                    }
+                    let room_messages = cloned_messages.entry(room).or_default();
+                    if set_reactions(room_messages, message_id, reactions) {
+                        messages_handle.set(cloned_messages);
+                    }
+                },
+                ServerMessage::Thread { message_id, messages, .. } => {
+                    if Some(message_id) == active_thread {
+                        cloned_thread_messages_handle.set(messages);
                     }
                 },
-                WebSocketMessageType::UsernameChange => {
-                    if let Some(username) = websocket_message.username {
-                        username_handle.set(username);
-                    } else {
-                        // TODO: add logs
-                        println!("Missing username payload");
-                        return; 
+                ServerMessage::Typing { room, username, typing: is_typing } => {
+                    let typing = cloned_typing_users.entry(room).or_default();
+                    typing.retain(|u| u != &username);
+                    if is_typing {
+                        typing.push(username);
                     }
+                    typing_users_handle.set(cloned_typing_users);
+                },
+                ServerMessage::Ack { message_id, delivered, .. } => {
+                    cloned_delivered.insert(message_id, delivered);
+                    delivered_handle.set(cloned_delivered);
+                },
+                ServerMessage::Read { room, username, message_id } => {
+                    let marker = cloned_read_markers.entry(room).or_default().entry(username).or_default();
+                    *marker = message_id.max(*marker);
+                    read_markers_handle.set(cloned_read_markers);
+                },
+                ServerMessage::Error { error, .. } => {
+                    cloned_error_handle.set(Some(error));
+                    error_timeout.reset();
                 },
+                ServerMessage::RoomList { .. } | ServerMessage::Welcome { .. } => {},
+            }
+        }
+    });
+
+    // Hello must be the first message on every (re)connect. The server
+    // forgets the presence of a closed connection, so it is sent again.
+    let cloned_ws = ws.clone();
+    let cloned_presence = presence.clone();
+    let cloned_auto_away = auto_away.clone();
+    use_effect_with((*ws.ready_state).clone(), move |ready_state| {
+        if *ready_state == UseWebSocketReadyState::Open {
+            let capabilities = CAPABILITIES.iter().map(|c| c.to_string()).collect();
+            let msg = ClientMessage::Hello { version: PROTOCOL_VERSION, capabilities };
+            cloned_ws.send(msg.to_string());
+            *cloned_auto_away.borrow_mut() = false;
+            if cloned_presence != Presence::default() {
+                cloned_ws.send(presence_msg(&cloned_presence).to_string());
             }
         }
     });
 
+    // An online user who does nothing for a while is shown as away, and
+    // as online again once they are back.
+    let cloned_ws = ws.clone();
+    let cloned_presence = presence.clone();
+    let cloned_auto_away = auto_away.clone();
+    let cloned_last_active = last_active.clone();
+    use_interval(move || {
+        let idle = Utc::now() - *cloned_last_active.borrow();
+        let mut auto_away = cloned_auto_away.borrow_mut();
+        if cloned_presence.status == PresenceStatus::Online
+            && !*auto_away
+            && idle >= TimeDelta::try_minutes(AUTO_AWAY_MINUTES).unwrap_or_default()
+        {
+            *auto_away = true;
+            let away = Presence { status: PresenceStatus::Away, ..cloned_presence.clone() };
+            cloned_ws.send(presence_msg(&away).to_string());
+        }
+    }, AUTO_AWAY_CHECK_MS);
+
+    let cloned_ws = ws.clone();
+    let cloned_presence = presence.clone();
+    let cloned_auto_away = auto_away.clone();
+    let on_activity = move |_: Event| {
+        *last_active.borrow_mut() = Utc::now();
+        let mut auto_away = cloned_auto_away.borrow_mut();
+        if *auto_away {
+            *auto_away = false;
+            cloned_ws.send(presence_msg(&cloned_presence).to_string());
+        }
+    };
+    use_event_with_window("mousemove", on_activity.clone());
+    use_event_with_window("keydown", on_activity.clone());
+    use_event_with_window("focus", on_activity);
+
+    let cloned_ws = ws.clone();
+    let on_presence = Callback::from(move |presence: Presence| {
+        *auto_away.borrow_mut() = false;
+        cloned_ws.send(presence_msg(&presence).to_string());
+        presence_handle.set(presence);
+    });
+
+    // Everything shown in the active room counts as read. The marker is
+    // sent on an interval, not for every message that comes in.
+    let cloned_ws = ws.clone();
+    let last_message_id = messages.get(&active_room).and_then(|m| m.iter().rev().find_map(|m| m.id));
+    let is_room_visible = active_direct.is_none();
+    let cloned_active_room = active_room.clone();
+    let read_sent = use_mut_ref(HashMap::<String, u64>::new);
+    use_interval(move || {
+        if let (Some(message_id), true) = (last_message_id, is_room_visible) {
+            let mut read_sent = read_sent.borrow_mut();
+            let sent = read_sent.entry(cloned_active_room.clone()).or_default();
+            if *sent < message_id {
+                *sent = message_id;
+                let msg = ClientMessage::Read { room: Some(cloned_active_room.clone()), message_id };
+                cloned_ws.send(msg.to_string());
+            }
+        }
+    }, READ_INTERVAL_MS);
+
     let cloned_ws = ws.clone();
     let send_message_callback = Callback::from(
-        move |msg: WebSocketMessage| {
+        move |msg: ClientMessage| {
             cloned_ws.send(msg.to_string());
         }
     );
 
+    let cloned_active_direct_handle = active_direct_handle.clone();
+    let cloned_active_thread_handle = active_thread_handle.clone();
+    let on_select_room = Callback::from(move |room: String| {
+        cloned_active_direct_handle.set(None);
+        cloned_active_thread_handle.set(None);
+        active_room_handle.set(room);
+    });
+
+    let cloned_active_thread_handle = active_thread_handle.clone();
+    let on_select_direct = Callback::from(move |user: String| {
+        cloned_active_thread_handle.set(None);
+        active_direct_handle.set(Some(user));
+    });
+
+    let cloned_ws = ws.clone();
+    let cloned_active_room = active_room.clone();
+    let cloned_active_thread_handle = active_thread_handle.clone();
+    let on_open_thread = Callback::from(move |message_id: u64| {
+        thread_messages_handle.set(Vec::new());
+        cloned_active_thread_handle.set(Some(message_id));
+        let msg = ClientMessage::Thread { room: Some(cloned_active_room.clone()), message_id };
+        cloned_ws.send(msg.to_string());
+    });
+
+    let on_close_thread = Callback::from(move |_: MouseEvent| {
+        active_thread_handle.set(None);
+    });
+
+    let cloned_ws = ws.clone();
+    let on_leave_room = Callback::from(move |room: String| {
+        cloned_ws.send(ClientMessage::LeaveRoom { room }.to_string());
+    });
+
+    let cloned_ws = ws.clone();
+    let cloned_active_room = active_room.clone();
+    let on_edit_message = Callback::from(move |(message_id, text): (u64, String)| {
+        let msg = ClientMessage::EditMessage { room: Some(cloned_active_room.clone()), message_id, text };
+        cloned_ws.send(msg.to_string());
+    });
+
+    let cloned_ws = ws.clone();
+    let cloned_active_room = active_room.clone();
+    let on_delete_message = Callback::from(move |message_id: u64| {
+        let msg = ClientMessage::DeleteMessage { room: Some(cloned_active_room.clone()), message_id };
+        cloned_ws.send(msg.to_string());
+    });
+
+    let cloned_ws = ws.clone();
+    let cloned_active_room = active_room.clone();
+    let on_react = Callback::from(move |(message_id, emoji, add): (u64, String, bool)| {
+        let room = Some(cloned_active_room.clone());
+        let msg = if add {
+            ClientMessage::AddReaction { room, message_id, emoji }
+        } else {
+            ClientMessage::RemoveReaction { room, message_id, emoji }
+        };
+        cloned_ws.send(msg.to_string());
+    });
+
+    let cloned_ws = ws.clone();
+    let cloned_active_room = active_room.clone();
+    let on_typing = Callback::from(move |is_typing: bool| {
+        let msg = ClientMessage::Typing { room: Some(cloned_active_room.clone()), typing: is_typing };
+        cloned_ws.send(msg.to_string());
+    });
+
+    let typing_notice = match typing_users.get(&active_room).map(Vec::as_slice).unwrap_or_default() {
+        [] => html! {},
+        [user] => html! { <p class="typing">{format!("{} is typing…", user)}</p> },
+        [first, second] => html! { <p class="typing">{format!("{} and {} are typing…", first, second)}</p> },
+        _ => html! { <p class="typing">{"Several people are typing…"}</p> },
+    };
+
+    let room_read_markers = read_markers.get(&active_room).cloned().unwrap_or_default();
+    let room_users = users.get(&active_room).cloned().unwrap_or_default();
+    let room_presence = users_presence.get(&active_room).cloned().unwrap_or_default();
+    let mut direct_chats: Vec<String> = direct_messages.keys().cloned().collect();
+    direct_chats.sort();
+
+    let chat_window = match (active_direct.clone(), active_thread) {
+        (Some(peer), _) => html! {
+            <div class="chat window">
+                <MessageList
+                    messages={direct_messages.get(&peer).cloned().unwrap_or_default()}
+                    title={format!("Direct messages with {}", peer)}
+                />
+                <Input 
+                    callback={send_message_callback.clone()}
+                    message_type={WebSocketMessageType::DirectMessage}
+                    wrapper_name="input-wrapper"
+                    placeholder={format!("Message {}...", peer)}
+                    button_text="Send"
+                    recipient={Some(peer)}
+                />
+            </div>
+        },
+        (None, Some(thread_id)) => html! {
+            <div class="chat window">
+                <span class="thread-close" onclick={on_close_thread}>{format!("← #{}", active_room)}</span>
+                <MessageList
+                    messages={thread_messages}
+                    title="Thread"
+                    username={username.clone()}
+                    delivered={delivered.clone()}
+                    read_markers={room_read_markers.clone()}
+                    on_edit={on_edit_message}
+                    on_delete={on_delete_message}
+                    on_react={on_react}
+                />
+                {typing_notice.clone()}
+                <Input 
+                    callback={send_message_callback.clone()}
+                    message_type={WebSocketMessageType::NewMessage}
+                    wrapper_name="input-wrapper"
+                    placeholder="Reply..."
+                    button_text="Reply"
+                    room={Some(active_room.clone())}
+                    reply_to={Some(thread_id)}
+                    on_typing={on_typing.clone()}
+                />
+            </div>
+        },
+        (None, None) => html! {
+            <div class="chat window">
+                <MessageList
+                    messages={messages.get(&active_room).cloned().unwrap_or_default()}
+                    username={username.clone()}
+                    delivered={delivered}
+                    read_markers={room_read_markers}
+                    on_edit={on_edit_message}
+                    on_delete={on_delete_message}
+                    on_react={on_react}
+                    on_open_thread={on_open_thread}
+                />
+                {typing_notice}
+                <Input 
+                    callback={send_message_callback.clone()}
+                    message_type={WebSocketMessageType::NewMessage}
+                    wrapper_name="input-wrapper"
+                    placeholder="Type message..."
+                    button_text="Send"
+                    room={Some(active_room.clone())}
+                    on_typing={on_typing}
+                />
+            </div>
+        },
+    };
+
+    let error_banner = match error {
+        Some(error) => html! {
+            <div class="error-banner">
+                <span>{error.text}</span>
+                <span class="error-close" onclick={move |_| error_handle.set(None)}>{"×"}</span>
+            </div>
+        },
+        None => html! {},
+    };
+
     html! {
         <div class="content">
+            {error_banner}
             <div class="chat-wrapper">
                 <div class="users window">
-                    <UsersList users={users} username={username}/>
+                    <RoomsList
+                        rooms={rooms}
+                        active_room={active_room.clone()}
+                        on_select={on_select_room}
+                        on_leave={on_leave_room}
+                        direct_chats={direct_chats}
+                        active_direct={active_direct}
+                        on_select_direct={on_select_direct.clone()}
+                    />
+                    <Input 
+                        callback={send_message_callback.clone()}
+                        message_type={WebSocketMessageType::JoinRoom}
+                        wrapper_name="join-room-wrapper"
+                        placeholder="Join room..."
+                        button_text="Join"
+                    />
+                    <UsersList users={room_users} presence={room_presence} username={username} on_select={on_select_direct}/>
+                    <PresencePicker presence={presence} on_change={on_presence}/>
                     <Input 
                         callback={send_message_callback.clone()}
                         message_type={WebSocketMessageType::UsernameChange}
@@ -87,16 +534,7 @@ fn App() -> Html {
                         button_text="Change"
                     />
                 </div>
-                <div class="chat window">
-                    <MessageList messages={messages}/>
-                    <Input 
-                        callback={send_message_callback.clone()}
-                        message_type={WebSocketMessageType::NewMessage}
-                        wrapper_name="input-wrapper"
-                        placeholder="Type message..."
-                        button_text="Send"
-                    />
-                </div>
+                {chat_window}
             </div>
         </div>
     }
@@ -1,17 +1,181 @@
+use std::collections::HashMap;
+
 use common::ChatMessage;
 use yew::prelude::*;
 
 
 #[derive(Properties, PartialEq)]
 pub struct MessageListProps {
-    pub messages: Vec<ChatMessage>
+    pub messages: Vec<ChatMessage>,
+    #[prop_or_else(|| "Messages".to_string())]
+    pub title: String,
+    /// Messages of this user get edit and delete controls.
+    #[prop_or_default]
+    pub username: String,
+    #[prop_or_default]
+    pub on_edit: Callback<(u64, String)>,
+    #[prop_or_default]
+    pub on_delete: Callback<u64>,
+    /// Emits the message id, the emoji and whether to add or remove it.
+    #[prop_or_default]
+    pub on_react: Callback<(u64, String, bool)>,
+    /// Set when messages can be opened as threads.
+    #[prop_or_default]
+    pub on_open_thread: Option<Callback<u64>>,
+    /// Number of users each own message was delivered to, by message id.
+    #[prop_or_default]
+    pub delivered: HashMap<u64, usize>,
+    /// Id of the last message every user has read, by username.
+    #[prop_or_default]
+    pub read_markers: HashMap<String, u64>,
+}
+
+const SNIPPET_LEN: usize = 40;
+
+fn reply_snippet(m: &ChatMessage, props: &MessageListProps) -> Html {
+    let parent_id = match m.reply_to {
+        Some(id) => id,
+        None => return html! {},
+    };
+    let text = match props.messages.iter().find(|p| p.id == Some(parent_id)) {
+        Some(parent) if parent.deleted => "message deleted".to_string(),
+        Some(parent) => {
+            let mut snippet: String = parent.message.chars().take(SNIPPET_LEN).collect();
+            if parent.message.chars().count() > SNIPPET_LEN {
+                snippet.push('…');
+            }
+            format!("{}: {}", parent.author, snippet)
+        },
+        None => "an earlier message".to_string(),
+    };
+    html! {
+        <p class="message-reply-to">{format!("↪ {}", text)}</p>
+    }
+}
+
+fn thread_link(m: &ChatMessage, props: &MessageListProps) -> Html {
+    let (message_id, on_open_thread) = match (m.id, &props.on_open_thread) {
+        (Some(id), Some(on_open_thread)) if !m.deleted => (id, on_open_thread.clone()),
+        _ => return html! {},
+    };
+    let replies = props.messages.iter().filter(|r| r.reply_to == Some(message_id)).count();
+    let text = match replies {
+        0 => "reply".to_string(),
+        1 => "1 reply".to_string(),
+        n => format!("{} replies", n),
+    };
+    let class = if replies == 0 { "message-thread" } else { "message-thread message-thread-active" };
+    html! {
+        <p {class} onclick={move |_| on_open_thread.emit(message_id)}>{text}</p>
+    }
+}
+
+const REACTION_EMOJIS: [&str; 4] = ["👍", "❤️", "😂", "🎉"];
+
+fn message_reactions(m: &ChatMessage, props: &MessageListProps) -> Html {
+    let message_id = match m.id {
+        Some(id) if !m.deleted && !props.username.is_empty() => id,
+        _ => return html! {},
+    };
+
+    html! {
+        <div class="message-reactions">
+            {
+                m.reactions.iter().map(|reaction| {
+                    let reacted = reaction.users.contains(&props.username);
+                    let class = if reacted { "reaction reaction-own" } else { "reaction" };
+                    let on_react = props.on_react.clone();
+                    let emoji = reaction.emoji.clone();
+                    html! {
+                        <span {class} title={reaction.users.join(", ")}
+                            onclick={move |_| on_react.emit((message_id, emoji.clone(), !reacted))}>
+                            {format!("{} {}", reaction.emoji, reaction.users.len())}
+                        </span>
+                    }
+                }).collect::<Html>()
+            }
+            <span class="reaction-picker">
+                {
+                    REACTION_EMOJIS.iter()
+                        .filter(|emoji| !m.reactions.iter().any(|r| r.emoji == **emoji))
+                        .map(|emoji| {
+                            let on_react = props.on_react.clone();
+                            let picked_emoji = emoji.to_string();
+                            html! {
+                                <span class="reaction-option"
+                                    onclick={move |_| on_react.emit((message_id, picked_emoji.clone(), true))}>
+                                    {emoji}
+                                </span>
+                            }
+                        }).collect::<Html>()
+                }
+            </span>
+        </div>
+    }
+}
+
+fn message_controls(m: &ChatMessage, props: &MessageListProps) -> Html {
+    let message_id = match m.id {
+        Some(id) if !m.deleted && !props.username.is_empty() && m.author == props.username => id,
+        _ => return html! {},
+    };
+
+    let on_edit = props.on_edit.clone();
+    let text = m.message.clone();
+    let on_edit_click = Callback::from(move |_: MouseEvent| {
+        let new_text = web_sys::window()
+            .and_then(|window| window.prompt_with_message_and_default("Edit message", &text).ok())
+            .flatten();
+        if let Some(new_text) = new_text {
+            if !new_text.is_empty() && new_text != text {
+                on_edit.emit((message_id, new_text));
+            }
+        }
+    });
+
+    let on_delete = props.on_delete.clone();
+    let on_delete_click = Callback::from(move |_: MouseEvent| {
+        on_delete.emit(message_id);
+    });
+
+    html! {
+        <p class="message-controls">
+            <span class="message-control" onclick={on_edit_click}>{"edit"}</span>
+            <span class="message-control" onclick={on_delete_click}>{"delete"}</span>
+        </p>
+    }
+}
+
+/// Sent, delivered or read state of an own message.
+fn message_status(m: &ChatMessage, props: &MessageListProps) -> Html {
+    let message_id = match m.id {
+        Some(id) if !m.deleted && !props.username.is_empty() && m.author == props.username => id,
+        _ => return html! {},
+    };
+
+    let mut readers: Vec<&String> = props.read_markers.iter()
+        .filter(|(user, last_read)| **user != props.username && **last_read >= message_id)
+        .map(|(user, _)| user)
+        .collect();
+    readers.sort();
+    let text = if !readers.is_empty() {
+        let readers: Vec<&str> = readers.into_iter().map(String::as_str).collect();
+        format!("read by {}", readers.join(", "))
+    } else if props.delivered.get(&message_id).copied().unwrap_or(0) > 0 {
+        "delivered".to_string()
+    } else {
+        "sent".to_string()
+    };
+    html! {
+        <p class="message-status">{text}</p>
+    }
 }
 
 #[function_component(MessageList)]
 pub fn get_message_list(props: &MessageListProps) -> Html {
     html! {
         <div class="messages">
-            <h3>{"Messages"}</h3>
+            <h3>{props.title.clone()}</h3>
             <ul id="chat">
                 {
                     props.messages.iter().map(|m| {
@@ -23,12 +187,28 @@ pub fn get_message_list(props: &MessageListProps) -> Html {
                                     <p class="message-text">{m.message.clone()}</p>
                                 </li>
                             }
+                        } else if m.deleted {
+                            html! {
+                                <li class="message">
+                                    <p class="message-timestamp">{m.created_at.format("%Y-%m-%d %H:%M").to_string()}</p>
+                                    <p class="message-author"><b>{m.author.clone()}</b></p>
+                                    <p class="message-text message-deleted">{"message deleted"}</p>
+                                </li>
+                            }
                         } else {
                             html! {
                                 <li class="message">
                                     <p class="message-timestamp">{m.created_at.format("%Y-%m-%d %H:%M").to_string()}</p>
                                     <p class="message-author"><b>{m.author.clone()}</b></p>
+                                    {reply_snippet(m, props)}
                                     <p class="message-text">{m.message.clone()}</p>
+                                    if m.edited_at.is_some() {
+                                        <p class="message-edited">{"(edited)"}</p>
+                                    }
+                                    {message_status(m, props)}
+                                    {message_controls(m, props)}
+                                    {message_reactions(m, props)}
+                                    {thread_link(m, props)}
                                 </li>
                             }
                         }
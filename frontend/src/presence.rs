@@ -0,0 +1,95 @@
+use web_sys::{HtmlInputElement, HtmlSelectElement};
+use yew::prelude::*;
+
+use common::{Presence, PresenceStatus};
+
+const STATUSES: &[(PresenceStatus, &str, &str)] = &[
+    (PresenceStatus::Online, "online", "Online"),
+    (PresenceStatus::Away, "away", "Away"),
+    (PresenceStatus::Dnd, "dnd", "Do not disturb"),
+];
+
+fn status_value(status: PresenceStatus) -> &'static str {
+    STATUSES.iter()
+        .find(|(s, _, _)| *s == status)
+        .map(|(_, value, _)| *value)
+        .unwrap_or_default()
+}
+
+/// Badge showing a user's presence, with its status text as tooltip.
+#[derive(PartialEq, Properties)]
+pub struct PresenceBadgeProps {
+    pub presence: Presence,
+}
+
+#[function_component(PresenceBadge)]
+pub fn get_presence_badge(props: &PresenceBadgeProps) -> Html {
+    let status = props.presence.status;
+    let title = STATUSES.iter()
+        .find(|(s, _, _)| *s == status)
+        .map(|(_, _, label)| label.to_string())
+        .unwrap_or_default();
+    html! {
+        <span class={classes!("presence-badge", status_value(status))} title={title}></span>
+    }
+}
+
+#[derive(PartialEq, Properties)]
+pub struct PresencePickerProps {
+    /// Presence chosen by the user, not the automatic away.
+    pub presence: Presence,
+    pub on_change: Callback<Presence>,
+}
+
+#[function_component(PresencePicker)]
+pub fn get_presence_picker(props: &PresencePickerProps) -> Html {
+    let PresencePickerProps { presence, on_change } = props;
+    let text_handle = use_state(|| presence.text.clone().unwrap_or_default());
+    let text = (*text_handle).clone();
+
+    let cloned_presence = presence.clone();
+    let cloned_on_change = on_change.clone();
+    let on_status_change = Callback::from(move |e: Event| {
+        let value = match e.target_dyn_into::<HtmlSelectElement>() {
+            Some(select) => select.value(),
+            None => return,
+        };
+        if let Some((status, _, _)) = STATUSES.iter().find(|(_, v, _)| *v == value) {
+            cloned_on_change.emit(Presence { status: *status, ..cloned_presence.clone() });
+        }
+    });
+
+    let cloned_text_handle = text_handle.clone();
+    let on_text_input = Callback::from(move |e: InputEvent| {
+        if let Some(input) = e.target_dyn_into::<HtmlInputElement>() {
+            cloned_text_handle.set(input.value());
+        }
+    });
+
+    let cloned_presence = presence.clone();
+    let cloned_on_change = on_change.clone();
+    let on_set_text = Callback::from(move |_: MouseEvent| {
+        let text = Some(text.trim().to_string()).filter(|text| !text.is_empty());
+        cloned_on_change.emit(Presence { text, ..cloned_presence.clone() });
+    });
+
+    html! {
+        <div class="presence-wrapper">
+            <select class="presence-select" onchange={on_status_change}>
+                {
+                    STATUSES.iter().map(|(status, value, label)| html! {
+                        <option value={*value} selected={*status == presence.status}>{*label}</option>
+                    }).collect::<Html>()
+                }
+            </select>
+            <input
+                type="text"
+                class="text-input"
+                placeholder="Set status..."
+                value={(*text_handle).clone()}
+                oninput={on_text_input}
+            />
+            <button type="submit" class="btn" onclick={on_set_text}>{"Set"}</button>
+        </div>
+    }
+}
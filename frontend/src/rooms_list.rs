@@ -0,0 +1,65 @@
+use yew::prelude::*;
+
+#[derive(PartialEq, Properties)]
+pub struct RoomsListProps {
+    pub rooms: Vec<String>,
+    pub active_room: String,
+    pub on_select: Callback<String>,
+    pub on_leave: Callback<String>,
+    pub direct_chats: Vec<String>,
+    pub active_direct: Option<String>,
+    pub on_select_direct: Callback<String>,
+}
+
+#[function_component(RoomsList)]
+pub fn get_rooms_list(props: &RoomsListProps) -> Html {
+    let RoomsListProps {
+        rooms,
+        active_room,
+        on_select,
+        on_leave,
+        direct_chats,
+        active_direct,
+        on_select_direct,
+    } = props;
+    html! {
+        <div class="rooms-list-wrapper">
+            <h3>{"Rooms"}</h3>
+            <ul class="rooms-list">
+                {
+                    rooms.iter().map(|room| {
+                        let is_active = active_direct.is_none() && room == active_room;
+                        let class = if is_active { "room room-active" } else { "room" };
+                        let select_room = room.clone();
+                        let on_select = on_select.clone();
+                        let leave_room = room.clone();
+                        let on_leave = on_leave.clone();
+                        html! {
+                            <li {class}>
+                                <span onclick={move |_| on_select.emit(select_room.clone())}>
+                                    {format!("#{}", room)}
+                                </span>
+                                <span class="room-leave" onclick={move |_| on_leave.emit(leave_room.clone())}>
+                                    {"×"}
+                                </span>
+                            </li>
+                        }
+                    }).collect::<Html>()
+                }
+                {
+                    direct_chats.iter().map(|user| {
+                        let is_active = active_direct.as_ref() == Some(user);
+                        let class = if is_active { "room room-active" } else { "room" };
+                        let selected_user = user.clone();
+                        let on_select_direct = on_select_direct.clone();
+                        html! {
+                            <li {class} onclick={move |_| on_select_direct.emit(selected_user.clone())}>
+                                {format!("@{}", user)}
+                            </li>
+                        }
+                    }).collect::<Html>()
+                }
+            </ul>
+        </div>
+    }
+}
@@ -1,27 +1,52 @@
+use std::collections::BTreeMap;
+
 use yew::prelude::*;
 
+use common::Presence;
+
+use crate::presence::PresenceBadge;
+
 #[derive(PartialEq, Properties)]
 pub struct UsersListProps {
     pub users: Vec<String>,
+    /// Presence of the users that are not simply online.
+    #[prop_or_default]
+    pub presence: BTreeMap<String, Presence>,
     pub username: String,
+    pub on_select: Callback<String>,
 }
 
 #[function_component(UsersList)]
 pub fn get_users_list(props: &UsersListProps) -> Html {
-    let UsersListProps { users, username } = props;
+    let UsersListProps { users, presence, username, on_select } = props;
+    let user_presence = |user: &str| presence.get(user).cloned().unwrap_or_default();
+    let status_text = |user: &str| match presence.get(user).and_then(|p| p.text.clone()) {
+        Some(text) => html! { <span class="presence-text">{text}</span> },
+        None => html! {},
+    };
     html! {
         <div class="users-list-wrapper">
             <h3>{"Active Users"}</h3>
             <ul class="users-list">
-                <li class="active-user">{username}<span class="active-user-you">{"You"}</span></li>
+                <li class="active-user">
+                    <PresenceBadge presence={user_presence(username)}/>
+                    {username}<span class="active-user-you">{"You"}</span>
+                    {status_text(username)}
+                </li>
                 {
                     users.iter().filter(|u| u.as_str() != username).map(|user| {
+                        let selected_user = user.clone();
+                        let on_select = on_select.clone();
                         html! {
-                            <li class="active-user">{user}</li>
+                            <li class="active-user" onclick={move |_| on_select.emit(selected_user.clone())}>
+                                <PresenceBadge presence={user_presence(user)}/>
+                                {user}
+                                {status_text(user)}
+                            </li>
                         }
                     }).collect::<Html>()
                 }
             </ul>
         </div>
     }
-}
\ No newline at end of file
+}